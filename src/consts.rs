@@ -18,9 +18,34 @@ fn main() {
 }
 "#;
 
+/// The template used for `--expr --pretty` input.
+pub const EXPR_PRETTY_TEMPLATE: &'static str = r#"
+fn main() {
+    println!("{:#?}", (%%));
+}
+"#;
+
+/// The template used for `--expr --json` input.
+pub const EXPR_JSON_TEMPLATE: &'static str = r#"
+extern crate serde_json;
+
+fn main() {
+    match serde_json::to_string(&(%%)) {
+        Ok(json) => println!("{}", json),
+        Err(err) => {
+            use std::io::Write;
+            let _ = writeln!(std::io::stderr(), "error serialising result: {}", err);
+            std::process::exit(1);
+        }
+    }
+}
+"#;
+
 /*
 Regarding the loop templates: what I *want* is for the result of the closure to be printed to standard output *only* if it's not `()`.
 
+Both split stdin on the byte substituted for `%b` (a newline, unless `--split` says otherwise), and stop at the end of stdin.  If stdin is empty, the closure is never called and nothing is printed.  A read error (such as invalid UTF-8) is reported and exits with status 1, rather than being mistaken for the end of input.
+
 * TODO: Just use TypeId, dumbass.
 * TODO: Merge the `LOOP_*` templates so there isn't duplicated code.  It's icky.
 */
@@ -31,13 +56,27 @@ use std::io::prelude::*;
 
 fn main() {
     let mut out_buffer: Vec<u8> = vec![];
-    let mut line_buffer = String::new();
-    let mut stdin = std::io::stdin();
+    let mut line_bytes: Vec<u8> = vec![];
+    let stdin = std::io::stdin();
+    let mut stdin = stdin.lock();
     loop {
-        line_buffer.clear();
-        let read_res = stdin.read_line(&mut line_buffer).unwrap_or(0);
+        line_bytes.clear();
+        let read_res = match stdin.read_until(%b, &mut line_bytes) {
+            Ok(n) => n,
+            Err(err) => {
+                let _ = writeln!(std::io::stderr(), "error reading stdin: {}", err);
+                std::process::exit(1);
+            }
+        };
         if read_res == 0 { break }
-        let output = invoke_closure(&line_buffer, %%);
+        let line_buffer = match std::str::from_utf8(&line_bytes) {
+            Ok(s) => s,
+            Err(err) => {
+                let _ = writeln!(std::io::stderr(), "error reading stdin: {}", err);
+                std::process::exit(1);
+            }
+        };
+        let output = invoke_closure(line_buffer, %%);
 
         out_buffer.clear();
         write!(&mut out_buffer, "{:?}", output).unwrap();
@@ -60,15 +99,29 @@ use std::io::prelude::*;
 
 fn main() {
     let mut out_buffer: Vec<u8> = vec![];
-    let mut line_buffer = String::new();
-    let mut stdin = std::io::stdin();
+    let mut line_bytes: Vec<u8> = vec![];
+    let stdin = std::io::stdin();
+    let mut stdin = stdin.lock();
     let mut count = 0;
     loop {
-        line_buffer.clear();
-        let read_res = stdin.read_line(&mut line_buffer).unwrap_or(0);
+        line_bytes.clear();
+        let read_res = match stdin.read_until(%b, &mut line_bytes) {
+            Ok(n) => n,
+            Err(err) => {
+                let _ = writeln!(std::io::stderr(), "error reading stdin: {}", err);
+                std::process::exit(1);
+            }
+        };
         if read_res == 0 { break }
+        let line_buffer = match std::str::from_utf8(&line_bytes) {
+            Ok(s) => s,
+            Err(err) => {
+                let _ = writeln!(std::io::stderr(), "error reading stdin: {}", err);
+                std::process::exit(1);
+            }
+        };
         count += 1;
-        let output = invoke_closure(&line_buffer, count, %%);
+        let output = invoke_closure(line_buffer, count, %%);
 
         out_buffer.clear();
         write!(&mut out_buffer, "{:?}", output).unwrap();
@@ -99,11 +152,121 @@ name = "%n"
 path = "%n.rs"
 "#;
 
+/**
+The default manifest used for packages built as a library with `--crate-type`.  `%l` is replaced with the library name, and `%t` with the crate type.
+*/
+pub const DEFAULT_LIB_MANIFEST: &'static str = r#"
+[package]
+name = "%n"
+version = "0.1.0"
+authors = ["Anonymous"]
+
+[lib]
+name = "%l"
+path = "%n.rs"
+crate-type = ["%t"]
+"#;
+
+/**
+The minimum Rust version (major, minor) needed for each edition we know about.  This is used to give a clear error *before* building when a script asks for an edition the toolchain can't handle.
+*/
+pub const EDITION_MIN_RUST_VERSIONS: &'static [(&'static str, (u64, u64))] = &[
+    ("2015", (1, 0)),
+    ("2018", (1, 31)),
+    ("2021", (1, 56)),
+    ("2024", (1, 85)),
+];
+
+/**
+Prefix of the environment variables used to pin the versions of dependencies given to `--dep` without one.
+*/
+pub const DEP_PIN_ENV_PREFIX: &'static str = "CARGO_SCRIPT_DEP_";
+
+/**
+The environment variable the `--post-run` hook can find the script's exit code in.
+*/
+pub const EXIT_CODE_ENV: &'static str = "CARGO_SCRIPT_EXIT_CODE";
+
+/**
+The environment variable which overrides where the cache lives.
+*/
+pub const CACHE_DIR_ENV: &'static str = "CARGO_SCRIPT_CACHE_DIR";
+
+/**
+The environment variable listing extra directories to look for scripts in, separated the same way as `PATH`.
+*/
+pub const SCRIPT_PATH_ENV: &'static str = "CARGO_SCRIPT_PATH";
+
+/**
+The environment variable which picks the profile (`debug` or `release`) scripts are built with when neither `--debug` nor `--release` is given.
+*/
+pub const PROFILE_ENV: &'static str = "CARGO_SCRIPT_PROFILE";
+
+/**
+The environment variable used to track how deeply nested an invocation is, for `--recursion-guard`.
+*/
+pub const DEPTH_ENV: &'static str = "CARGO_SCRIPT_DEPTH";
+
+/**
+The sanitizers that can be passed to `--sanitizer`.
+*/
+pub const SANITIZERS: &'static [&'static str] = &["address", "leak", "memory", "thread"];
+
+/**
+The crate types that can be passed to `--crate-type`.
+*/
+pub const CRATE_TYPES: &'static [&'static str] = &["cdylib", "dylib", "staticlib"];
+
 /**
 The name of the package metadata file.
 */
 pub const METADATA_FILE: &'static str = "metadata.json";
 
+/**
+The name of the lockfile Cargo writes into a package, recording which versions of its dependencies were used.
+*/
+pub const CARGO_LOCK_FILE: &'static str = "Cargo.lock";
+
+/**
+The name of the directory in a package's cache folder which acts as a lock while it's being built.
+*/
+pub const LOCK_DIR: &'static str = "lock";
+
+/**
+How long to wait, in milliseconds, between attempts to take a package's lock.
+*/
+pub const LOCK_POLL_MS: u32 = 100;
+
+/**
+How long to wait, in milliseconds, for another build to release a package's lock before giving up.
+*/
+pub const LOCK_TIMEOUT_MS: u32 = 10 * 60 * 1000;
+
+/**
+How often to check, in milliseconds, whether the script has changed for `--watch`.
+*/
+pub const WATCH_POLL_MS: u32 = 500;
+
+/**
+The name of the file in the cache folder which gets touched to find out what time it is.
+*/
+pub const TIMESTAMP_FILE: &'static str = "timestamp";
+
+/**
+How old a cached package can get, in milliseconds, before it's removed.  This can be changed with `--max-cache-age`.
+*/
+pub const MAX_CACHE_AGE_MS: u64 = 7 * 24 * 60 * 60 * 1000;
+
+/**
+What a script's directive line, giving extra flags to run it with, can start with.
+*/
+pub const SCRIPT_DIRECTIVE_PREFIXES: &'static [&'static str] = &["//! cargo-script:", "// cargo-script:"];
+
+/**
+The name of the project config file, which is looked for in the current directory and its parents.
+*/
+pub const PROJECT_CONFIG_FILE: &'static str = ".cargo-script.toml";
+
 /**
 Extensions to check when trying to find script input by name.
 */
@@ -14,6 +14,8 @@ pub enum MainError {
     Io(Blame, io::Error),
     OtherOwned(Blame, String),
     OtherBorrowed(Blame, &'static str),
+    /// Cargo failed to build the named script, exiting with the given status.  This is always the user's fault.
+    Compile(String, i32),
 }
 
 /**
@@ -31,6 +33,17 @@ impl MainError {
             Io(blame, _)
             | OtherOwned(blame, _)
             | OtherBorrowed(blame, _) => blame == Blame::Human,
+            Compile(..) => true,
+        }
+    }
+
+    /**
+    The status to exit with because of this error.  Build failures pass on Cargo's status, so that anything running us can tell them apart.
+    */
+    pub fn exit_code(&self) -> i32 {
+        match *self {
+            MainError::Compile(_, code) => code,
+            _ => 1
         }
     }
 }
@@ -43,6 +56,7 @@ impl fmt::Display for MainError {
             Io(_, ref err) => Display::fmt(err, fmt),
             OtherOwned(_, ref err) => Display::fmt(err, fmt),
             OtherBorrowed(_, ref err) => Display::fmt(err, fmt),
+            Compile(ref name, code) => write!(fmt, "failed to compile `{}` (cargo exited with status {})", name, code),
         }
     }
 }
@@ -54,6 +68,7 @@ impl Error for MainError {
             Io(_, ref err) => err.description(),
             OtherOwned(_, ref err) => err,
             OtherBorrowed(_, ref err) => err,
+            Compile(..) => "failed to compile script",
         }
     }
 }
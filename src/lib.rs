@@ -0,0 +1,2868 @@
+/*!
+The guts of `cargo-script`: splitting scripts, generating and caching their packages, and building them.
+
+This is what the `cargo-script` binary is built on, but it can also be used directly by other programs which want to run Rust scripts without shelling out.  The simplest way in is `build_input`, which takes an `Input` and its `PackageMetadata`, makes sure it's been built, and returns the path to the executable.
+*/
+#![allow(deprecated)] // for file metadata
+#![feature(collections)]
+#![feature(duration_span)]
+#![feature(fs_time)]
+#![feature(path_ext)]
+
+extern crate flate2;
+#[macro_use] extern crate log;
+extern crate rustc_serialize;
+extern crate shaman;
+extern crate toml;
+
+/**
+If this is set to `true`, the digests used for package IDs will be replaced with "stub" to make testing a bit easier.  Obviously, you don't want this `true` for release...
+*/
+const STUB_HASHES: bool = false;
+
+pub mod consts;
+pub mod error;
+mod platform;
+pub mod util;
+
+use std::borrow::Cow;
+use std::error::Error;
+use std::ffi::OsString;
+use std::fs;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use error::{Blame, MainError};
+
+pub type Result<T> = std::result::Result<T, MainError>;
+
+/**
+When to colour output.
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq, RustcDecodable)]
+pub enum Color {
+    Auto,
+    Always,
+    Never,
+}
+
+impl Color {
+    /**
+    Should our own output to stdout be coloured?
+    */
+    pub fn enabled(self) -> bool {
+        match self {
+            Color::Auto => platform::stdout_supports_color(),
+            Color::Always => true,
+            Color::Never => false,
+        }
+    }
+
+    /**
+    Returns the value to pass to Cargo's `--color` flag.
+    */
+    pub fn as_cargo_arg(self) -> &'static str {
+        match self {
+            Color::Auto => "auto",
+            Color::Always => "always",
+            Color::Never => "never",
+        }
+    }
+}
+
+/**
+If the line is a directive line, returns the flags it gives.
+*/
+pub fn strip_script_directive(line: &str) -> Option<&str> {
+    consts::SCRIPT_DIRECTIVE_PREFIXES.iter()
+        .find(|prefix| line.starts_with(**prefix))
+        .map(|prefix| &line[prefix.len()..])
+}
+
+/**
+Returns the environment variables a manifest asks to be set when running the script.
+
+These come from `[package.metadata.cargo-script.env]`, and override anything inherited from our own environment.  They have no bearing on how the package is built.
+*/
+pub fn manifest_env(mani: &toml::Table) -> Result<Vec<(String, String)>> {
+    let env = match mani.get("package")
+        .and_then(|p| p.lookup("metadata.cargo-script.env"))
+    {
+        Some(env) => env,
+        None => return Ok(vec![])
+    };
+
+    let env = match env.as_table() {
+        Some(env) => env,
+        None => return Err((Blame::Human,
+            "`package.metadata.cargo-script.env` must be a table").into())
+    };
+
+    let mut vars = vec![];
+    for (k, v) in env {
+        match v.as_str() {
+            Some(v) => vars.push((k.clone(), v.into())),
+            None => return Err((Blame::Human,
+                format!("environment variable `{}` in the manifest must be a string", k)).into())
+        }
+    }
+    Ok(vars)
+}
+
+/**
+Load the generated manifest, given the path to the package's cache folder.
+*/
+pub fn get_pkg_manifest<P>(pkg_path: P) -> Result<toml::Table>
+where P: AsRef<Path> {
+    let mani_path = pkg_path.as_ref().join("Cargo.toml");
+    let mut mani_str = String::new();
+    try!(try!(fs::File::open(&mani_path)).read_to_string(&mut mani_str));
+    toml::Parser::new(&mani_str).parse()
+        .ok_or("could not parse generated manifest".into())
+}
+
+/**
+Returns the names of the binaries declared in a manifest.
+
+Like Cargo, a binary without a name of its own is named after the package, which the script's manifest is free to change.
+*/
+pub fn manifest_bin_names(mani: &toml::Table) -> Vec<String> {
+    let bins = match mani.get("bin").and_then(|b| b.as_slice()) {
+        Some(bins) => bins,
+        None => return vec![]
+    };
+    let pkg_name = manifest_pkg_name(mani);
+
+    bins.iter()
+        .filter_map(|bin| bin.lookup("name").and_then(|n| n.as_str()).or(pkg_name))
+        .map(|n| n.into())
+        .collect()
+}
+
+/**
+Returns the package name from a manifest, if it has one.
+*/
+fn manifest_pkg_name(mani: &toml::Table) -> Option<&str> {
+    mani.get("package")
+        .and_then(|p| p.lookup("name"))
+        .and_then(|n| n.as_str())
+}
+
+/**
+Removes the package in the given cache folder if it has a metadata file we can't make sense of.  In that case, nothing else in it can be trusted either, so it's best to start again from scratch.
+
+This must only be done while holding the package's lock, which is left where it is; it's not ours to remove.  This can't fail; if the package can't be removed, building it will find out soon enough.
+*/
+fn remove_corrupt_package(pkg_path: &Path) {
+    use std::fs::PathExt;
+
+    if !pkg_path.join(consts::METADATA_FILE).exists() {
+        return;
+    }
+    if let Err(err) = get_pkg_metadata(pkg_path) {
+        warn!("removing package {:?} with corrupt metadata: {}", pkg_path, err);
+        if let Err(err) = clear_locked_package(pkg_path) {
+            warn!("could not remove {:?}: {}", pkg_path, err);
+        }
+    }
+}
+
+/**
+Removes everything in the given package's cache folder, except for its lock.
+*/
+fn clear_locked_package(pkg_path: &Path) -> Result<()> {
+    use std::fs::PathExt;
+
+    let lock_path = pkg_path.join(consts::LOCK_DIR);
+    for entry in try!(fs::read_dir(pkg_path)) {
+        let path = try!(entry).path();
+        if path == lock_path {
+            continue;
+        }
+        match path.is_dir() {
+            true => try!(fs::remove_dir_all(&path)),
+            false => try!(fs::remove_file(&path))
+        }
+    }
+    Ok(())
+}
+
+/**
+Locks the package in the given cache folder, waiting up to `timeout_ms` milliseconds if someone else already holds the lock.  Returns the path to the lock, which must be removed to unlock it again.
+
+The lock is a directory, since creating one is atomic, and fails if it already exists.  If a previous run died while holding the lock, there's no telling it apart from a long build, so we give up after a while and tell the user where the lock is; unless `quiet`, we also say so while waiting.
+*/
+fn lock_package<P>(pkg_path: P, quiet: bool, timeout_ms: u32) -> Result<PathBuf>
+where P: AsRef<Path> {
+    let pkg_path = pkg_path.as_ref();
+    try!(fs::create_dir_all(pkg_path));
+
+    let lock_path = pkg_path.join(consts::LOCK_DIR);
+    let mut waited_ms = 0;
+    loop {
+        match fs::create_dir(&lock_path) {
+            Ok(()) => break,
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => {
+                if waited_ms >= timeout_ms {
+                    return Err((Blame::Human,
+                        format!("gave up waiting for another build of this script to finish; \
+                            if there isn't one, remove {:?}", lock_path)).into());
+                }
+                if waited_ms == 0 && !quiet {
+                    warn!("waiting for another build of this script to finish (if there isn't one, remove {:?})",
+                        lock_path);
+                }
+                std::thread::sleep_ms(consts::LOCK_POLL_MS);
+                waited_ms += consts::LOCK_POLL_MS;
+            },
+            Err(err) => return Err(err.into())
+        }
+    }
+
+    info!("locked {:?}", lock_path);
+    Ok(lock_path)
+}
+
+/**
+Builds the input if it isn't already cached, and returns the path to its executable.  This is everything `cargo script` does, short of actually running it.
+*/
+pub fn build_input(input: &Input, meta: PackageMetadata, settings: &BuildSettings) -> Result<PathBuf> {
+    let (action, pkg_path, meta) = try!(cache_action_for(input, meta, false, false));
+    let meta = try!(ensure_compiled(input, &action, &pkg_path, meta, false, false, settings));
+    get_exe_path(input, &pkg_path, &meta)
+}
+
+/**
+Makes sure the package for the input is built, given what `cache_action_for` decided to do with it.  Returns the metadata for the built package.
+
+The package is locked for the whole build, so that another run of the same script waits rather than trampling all over it.  Having waited, whoever held the lock may well have built it already.
+*/
+pub fn ensure_compiled(input: &Input, action: &CacheAction, pkg_path: &Path, meta: PackageMetadata,
+    force: bool, verify: bool, settings: &BuildSettings) -> Result<PackageMetadata> {
+    if *action != CacheAction::Compile && !force {
+        return Ok(meta);
+    }
+
+    let lock_path = try!(lock_package(pkg_path, settings.quiet, consts::LOCK_TIMEOUT_MS));
+    let _lock = util::Defer::<_, MainError>::defer(move || {
+        info!("unlocking {:?}", lock_path);
+        fs::remove_dir(&lock_path).map_err(Into::into)
+    });
+
+    // Only now that nobody else can be building it is it safe to throw away.
+    remove_corrupt_package(pkg_path);
+
+    let (action, _, cached_meta) = try!(cache_action_for(input, meta.clone(), false, verify));
+    if action == CacheAction::Compile || force {
+        info!("compiling...");
+        compile(input, &meta, pkg_path, settings)
+    } else {
+        info!("package was built while we waited for the lock");
+        Ok(cached_meta)
+    }
+}
+
+/**
+Compile a package from the input.
+
+Why take `PackageMetadata`?  To ensure that any information we need to depend on for compilation *first* passes through `cache_action_for` *and* is less likely to not be serialised with the rest of the metadata.  The exceptions go in `BuildSettings`, since they can't change what gets built.
+*/
+pub fn compile<P>(input: &Input, meta: &PackageMetadata, pkg_path: P, settings: &BuildSettings) -> Result<PackageMetadata>
+where P: AsRef<Path> {
+    let pkg_path = pkg_path.as_ref();
+    try!(generate_package(input, meta, pkg_path, settings));
+
+    // Work out which binary we're after *before* starting Cargo, so that there's nothing to clean up if we can't.  A script with several binaries and nothing to pick between them (as with `--run-all`) doesn't have just the one.
+    let bin = match meta.crate_type {
+        Some(_) => None,
+        None => try!(find_default_bin(input, &try!(get_pkg_manifest(pkg_path)), meta))
+    };
+    info!("bin: {:?}", bin);
+
+    // *bursts through wall* It's Cargo Time!
+    let mut cmd = cargo_command("build", pkg_path, meta, settings);
+
+    // Ask Cargo where it actually put the executable, rather than guessing.  Diagnostics still get shown as normal.
+    cmd.arg("--message-format=json-render-diagnostics");
+    cmd.stdout(Stdio::piped());
+
+    // If Cargo runs at all, any failure is down to the script.
+    let mut child = try!(cmd.spawn()
+        .map_err(|err| (Blame::Human, format!("could not run cargo: {}", err))));
+    let built_exe = {
+        let child_out = child.stdout.take().expect("cargo stdout was not captured");
+        let mut built_exe = None;
+        for line in std::io::BufReader::new(child_out).lines() {
+            let line = try!(line);
+            if let Some(ref bin) = bin {
+                if let Some(exe) = built_exe_from_message(&line, bin) {
+                    built_exe = Some(exe);
+                }
+            }
+        }
+        built_exe
+    };
+    info!("built_exe: {:?}", built_exe);
+
+    let status = try!(child.wait());
+    match status.code() {
+        Some(0) => (),
+        Some(code) => {
+            // The most likely reason for this is the expression's type, which is easy to miss.
+            if let (&Input::Expr(_, ExprStyle::Json), false) = (input, settings.quiet) {
+                warn!("with --json, the expression's type must implement `serde::Serialize`");
+            }
+            return Err(MainError::Compile(input.safe_name(), code));
+        },
+        None => try!(Err((Blame::Human,
+            format!("cargo was stopped before it finished building `{}`", input.safe_name()))))
+    }
+
+    // Record where the executable is, and what it looks like, so we can tell if it gets damaged later.  With several binaries, each is just expected wherever Cargo usually puts it.
+    let mut meta = meta.clone();
+    meta.exe_path = built_exe;
+    if meta.crate_type.is_some() || bin.is_some() {
+        let exe_path = try!(get_artifact_path(input, pkg_path, &meta));
+        meta.exe_size = Some(try!(fs::metadata(&exe_path)).len());
+        meta.exe_hash = Some(try!(hash_file(&exe_path)));
+    }
+
+    // Also record which versions of the dependencies it was built against.
+    meta.lock_hash = hash_file(pkg_path.join(consts::CARGO_LOCK_FILE)).ok();
+
+    // Write out metadata *now*.  Remember that we check the timestamp in the metadata, *not* on the executable.
+    if settings.write_metadata {
+        try!(write_pkg_metadata(pkg_path, &meta));
+    }
+
+    Ok(meta)
+}
+
+/**
+Creates a command which runs a Cargo subcommand that builds the package in the given cache folder (such as `build`, `check`, or `test`), passing it everything the metadata and settings say to build with.
+*/
+pub fn cargo_command(subcommand: &str, pkg_path: &Path, meta: &PackageMetadata, settings: &BuildSettings) -> Command {
+    let mani_path = pkg_path.join("Cargo.toml");
+    let mut cmd = toolchain_command("cargo", meta.toolchain());
+    cmd.arg(subcommand)
+        .arg("--manifest-path")
+        .arg(&*mani_path.to_string_lossy());
+
+    if !meta.debug {
+        cmd.arg("--release");
+    }
+
+    if let Some(ref target) = meta.target {
+        cmd.arg("--target").arg(target);
+    }
+
+    if let Some(ref bin) = meta.bin {
+        cmd.arg("--bin").arg(bin);
+    }
+
+    if meta.features.len() > 0 {
+        cmd.arg("--features").arg(meta.features.join(" "));
+    }
+
+    if let Some(jobs) = settings.jobs {
+        cmd.arg("--jobs").arg(jobs.to_string());
+    }
+
+    cmd.arg("--color").arg(settings.color.as_cargo_arg());
+
+    if settings.quiet_cargo {
+        cmd.arg("--quiet");
+    }
+
+    if settings.offline {
+        cmd.arg("--offline");
+    }
+
+    set_build_env(&mut cmd, meta, pkg_path);
+    cmd
+}
+
+/**
+If the line is one of Cargo's JSON messages saying it has built the executable for the named binary, returns the path to it.
+*/
+fn built_exe_from_message(line: &str, bin: &str) -> Option<String> {
+    use rustc_serialize::json::Json;
+
+    let msg = match Json::from_str(line) {
+        Ok(msg) => msg,
+        Err(_) => return None
+    };
+
+    if msg.find("reason").and_then(|r| r.as_string()) != Some("compiler-artifact") {
+        return None;
+    }
+    if msg.find_path(&["target", "name"]).and_then(|n| n.as_string()) != Some(bin) {
+        return None;
+    }
+    msg.find("executable").and_then(|e| e.as_string()).map(String::from)
+}
+
+/**
+Writes out the package for the input (manifest and source) into the given cache folder, without building it.  Returns the path to the manifest.
+*/
+pub fn generate_package<P>(input: &Input, meta: &PackageMetadata, pkg_path: P, settings: &BuildSettings) -> Result<PathBuf>
+where P: AsRef<Path> {
+    let pkg_path = pkg_path.as_ref();
+
+    let (mani_str, script_str) = try!(split_input(input, meta, settings));
+
+    if settings.check_edition {
+        try!(check_edition_support(&mani_str, meta.toolchain()));
+    }
+
+    try!(fs::create_dir_all(pkg_path));
+
+    let mani_path = {
+        let mani_path = pkg_path.join("Cargo.toml");
+        let mut mani_f = try!(fs::File::create(&mani_path));
+        try!(write!(&mut mani_f, "{}", mani_str));
+        try!(mani_f.flush());
+        mani_path
+    };
+
+    {
+        let script_path = pkg_path.join(input.safe_name()).with_extension("rs");
+        info!("script_path: {:?}", script_path);
+        let mut script_f = try!(fs::File::create(script_path));
+        try!(write!(&mut script_f, "{}", script_str));
+        try!(script_f.flush());
+    }
+
+    if let Some(ref feature) = meta.features_all_deps {
+        try!(enable_feature_on_deps(&mani_path, meta.toolchain(), settings.offline, feature));
+    }
+
+    Ok(mani_path)
+}
+
+/**
+Sets up the environment for a Cargo command which builds the package in the given cache folder.
+*/
+fn set_build_env(cmd: &mut Command, meta: &PackageMetadata, pkg_path: &Path) {
+    for (k, v) in build_env(meta, pkg_path) {
+        cmd.env(k, v);
+    }
+}
+
+/**
+Works out the environment variables `set_build_env` sets.
+*/
+fn build_env(meta: &PackageMetadata, pkg_path: &Path) -> Vec<(&'static str, String)> {
+    let mut env = vec![];
+
+    let extra_rustflags = rustflags_for(meta, pkg_path);
+    if extra_rustflags.len() > 0 {
+        env.push(("RUSTFLAGS", merge_rustflags(extra_rustflags)));
+    }
+
+    if meta.reproducible {
+        let epoch = std::env::var("SOURCE_DATE_EPOCH").unwrap_or("0".into());
+        env.push(("SOURCE_DATE_EPOCH", epoch));
+        env.push(("CARGO_INCREMENTAL", "0".into()));
+    }
+    env
+}
+
+/**
+Appends extra flags to whatever is already in `RUSTFLAGS`.
+*/
+fn merge_rustflags(extra: Vec<String>) -> String {
+    let mut rustflags = std::env::var("RUSTFLAGS").unwrap_or(String::new());
+    for flag in extra {
+        rustflags.push_str(" ");
+        rustflags.push_str(&flag);
+    }
+    let rustflags = rustflags.trim().to_owned();
+    info!("RUSTFLAGS: {:?}", rustflags);
+    rustflags
+}
+
+/**
+Works out which flags need to be passed to `rustc` (in addition to anything already in `RUSTFLAGS`) to build the package.
+*/
+fn rustflags_for(meta: &PackageMetadata, pkg_path: &Path) -> Vec<String> {
+    let mut flags = vec![];
+
+    if let Some(ref sanitizer) = meta.sanitizer {
+        flags.push(format!("-Z sanitizer={}", sanitizer));
+    }
+
+    if let Some(ref features) = meta.target_features {
+        flags.push(format!("-C target-feature={}", features));
+    }
+
+    flags.extend(meta.rustc_flags.iter().cloned());
+
+    // The cache and Cargo's registry are the paths most likely to differ between machines.  Note that `RUSTFLAGS` is split on spaces, so this won't help if they contain any.
+    if meta.reproducible {
+        flags.push(format!("--remap-path-prefix={}=.", pkg_path.display()));
+
+        let cargo_home = std::env::var_os("CARGO_HOME").map(PathBuf::from)
+            .or_else(|| std::env::home_dir().map(|home| home.join(".cargo")));
+        if let Some(cargo_home) = cargo_home {
+            flags.push(format!("--remap-path-prefix={}=/cargo", cargo_home.display()));
+        }
+    }
+
+    flags
+}
+
+/**
+Enables `feature` on every direct dependency in the manifest at `mani_path` which actually *has* that feature, rewriting the manifest in place.
+
+There's no way to know which dependencies have which features without asking Cargo, so this resolves the package with `cargo metadata` first.
+*/
+fn enable_feature_on_deps(mani_path: &Path, toolchain: Option<&str>, offline: bool, feature: &str) -> Result<()> {
+    use rustc_serialize::json::Json;
+
+    let mut cmd = toolchain_command("cargo", toolchain);
+    cmd.arg("metadata")
+        .arg("--format-version").arg("1")
+        .arg("--manifest-path")
+        .arg(&*mani_path.to_string_lossy());
+    if offline {
+        cmd.arg("--offline");
+    }
+    let out = try!(cmd.output());
+    if !out.status.success() {
+        try!(Err(format!("cargo metadata failed: {}", String::from_utf8_lossy(&out.stderr))));
+    }
+
+    let cargo_meta = try!(Json::from_str(&String::from_utf8_lossy(&out.stdout))
+        .map_err(|err| err.to_string()));
+
+    let has_feature: Vec<&str> = cargo_meta.find("packages")
+        .and_then(|pkgs| pkgs.as_array())
+        .map(|pkgs| pkgs.iter()
+            .filter(|pkg| pkg.find_path(&["features", feature]).is_some())
+            .filter_map(|pkg| pkg.find("name").and_then(|name| name.as_string()))
+            .collect())
+        .unwrap_or(vec![]);
+    info!("packages with feature {:?}: {:?}", feature, has_feature);
+
+    let mut mani = {
+        let mut mani_str = String::new();
+        try!(try!(fs::File::open(mani_path)).read_to_string(&mut mani_str));
+        try!(toml::Parser::new(&mani_str).parse()
+            .ok_or("could not parse generated manifest"))
+    };
+
+    add_feature_to_deps(&mut mani, &has_feature, feature);
+
+    let mut mani_f = try!(fs::File::create(mani_path));
+    try!(write!(&mut mani_f, "{}", toml::Value::Table(mani)));
+    try!(mani_f.flush());
+    Ok(())
+}
+
+/**
+Enables `feature` on each of the manifest's direct dependencies named in `has_feature`.
+*/
+fn add_feature_to_deps(mani: &mut toml::Table, has_feature: &[&str], feature: &str) {
+    if let Some(&mut toml::Value::Table(ref mut deps)) = mani.get_mut("dependencies") {
+        for (name, dep) in deps.iter_mut() {
+            if !has_feature.contains(&&**name) {
+                continue;
+            }
+
+            // Dependencies can be given as just a version, or as a table.
+            let mut dep_t = match *dep {
+                toml::Value::String(ref version) => {
+                    let mut dep_t = toml::Table::new();
+                    dep_t.insert("version".into(), toml::Value::String(version.clone()));
+                    dep_t
+                },
+                toml::Value::Table(ref dep_t) => dep_t.clone(),
+                _ => continue
+            };
+
+            let mut features = match dep_t.remove("features") {
+                Some(toml::Value::Array(features)) => features,
+                _ => vec![]
+            };
+            let feature = toml::Value::String(feature.into());
+            if !features.contains(&feature) {
+                features.push(feature);
+            }
+            dep_t.insert("features".into(), toml::Value::Array(features));
+
+            *dep = toml::Value::Table(dep_t);
+        }
+    }
+}
+
+/**
+Checks that the active Rust toolchain is new enough for the edition requested in the manifest, if any.
+
+This is purely so the user gets a sensible error *before* Cargo gets halfway through the build and falls over.  Editions we don't know about are let through; Cargo can complain about those itself.
+*/
+fn check_edition_support(mani_str: &str, toolchain: Option<&str>) -> Result<()> {
+    check_edition_support_with(mani_str, || get_rustc_version(toolchain))
+}
+
+/**
+Does the work for `check_edition_support`, calling `rustc_version` to find out what version of Rust we have.  It's only called if there's an edition to check.
+*/
+fn check_edition_support_with<F>(mani_str: &str, rustc_version: F) -> Result<()>
+where F: FnOnce() -> Result<(u64, u64)> {
+    let mani = try!(toml::Parser::new(mani_str).parse()
+        .ok_or("could not parse generated manifest"));
+    let mani = toml::Value::Table(mani);
+
+    let edition = match mani.lookup("package.edition").and_then(|v| v.as_str()) {
+        Some(edition) => edition,
+        None => return Ok(())
+    };
+
+    let min_ver = match consts::EDITION_MIN_RUST_VERSIONS.iter().find(|&&(ed, _)| ed == edition) {
+        Some(&(_, min_ver)) => min_ver,
+        None => {
+            info!("not checking unknown edition {:?}", edition);
+            return Ok(());
+        }
+    };
+
+    let rustc_ver = try!(rustc_version());
+    info!("rustc_ver: {:?}", rustc_ver);
+
+    if rustc_ver < min_ver {
+        try!(Err((Blame::Human,
+            format!("edition {} requires Rust {}.{}+, found {}.{}",
+                edition, min_ver.0, min_ver.1, rustc_ver.0, rustc_ver.1))));
+    }
+
+    Ok(())
+}
+
+/**
+Creates a command for one of the Rust tools (`cargo` or `rustc`), using the given rustup toolchain if there is one.
+*/
+pub fn toolchain_command(program: &str, toolchain: Option<&str>) -> Command {
+    let mut cmd = Command::new(program);
+    if let Some(toolchain) = toolchain {
+        cmd.arg(format!("+{}", toolchain));
+    }
+    cmd
+}
+
+/**
+Asks `rustc` what version it is, returning the major and minor version numbers.
+*/
+fn get_rustc_version(toolchain: Option<&str>) -> Result<(u64, u64)> {
+    let release = try!(get_rustc_version_info(toolchain, "release"));
+    parse_rustc_version(&release)
+        .ok_or(format!("could not understand rustc version {:?}", release).into())
+}
+
+/**
+Asks `rustc` for one of the fields from its verbose version information (*e.g.* `release` or `host`).
+*/
+pub fn get_rustc_version_info(toolchain: Option<&str>, key: &str) -> Result<String> {
+    let out = try!(toolchain_command("rustc", toolchain).arg("-vV").output());
+    if !out.status.success() {
+        try!(Err("could not get rustc version"));
+    }
+    let out = String::from_utf8_lossy(&out.stdout);
+
+    for line in out.lines() {
+        let mut parts = line.splitn(2, ':');
+        if parts.next().map(|k| k.trim()) == Some(key) {
+            return Ok(parts.next().unwrap_or("").trim().into());
+        }
+    }
+
+    Err(format!("rustc did not report its {}", key).into())
+}
+
+/**
+Parses a `rustc` release version (*e.g.* `1.56.0-nightly`) into major and minor version numbers.
+*/
+fn parse_rustc_version(s: &str) -> Option<(u64, u64)> {
+    let mut parts = s.trim().split('-').next().unwrap_or("").split('.');
+    let major = parts.next().and_then(|p| p.parse().ok());
+    let minor = parts.next().and_then(|p| p.parse().ok());
+    match (major, minor) {
+        (Some(major), Some(minor)) => Some((major, minor)),
+        _ => None
+    }
+}
+
+/**
+Splits input into a complete Cargo manifest and unadultered Rust source.
+
+Anything in the embedded manifest which can't work in a script is rejected, or warned about (unless `settings.quiet`).
+*/
+pub fn split_input(input: &Input, meta: &PackageMetadata, settings: &BuildSettings) -> Result<(String, String)> {
+    let (part_mani, source, template) = match *input {
+        Input::File(_, _, content, _) | Input::Stdin(content) => {
+            let split = try!(split_script(content)
+                .ok_or("could not locate start of Rust source in script"));
+            info!("split: {:?}", split);
+            (split.manifest, split.source, input.template())
+        },
+        Input::Expr(content, _) | Input::Loop(content, _, _) => ("".into(), content, input.template()),
+    };
+
+    let template = match (input, &meta.template) {
+        (&Input::Expr(..), &Some(ref user_templ)) | (&Input::Loop(..), &Some(ref user_templ))
+            => &**user_templ,
+        _ => template
+    };
+
+    // The separator has to go in first, in case the closure itself happens to contain `%b`.
+    let template = match *input {
+        Input::Loop(_, _, sep) => template.replace("%b", &format!("{}u8", sep)),
+        _ => template.into()
+    };
+
+    let source = template.replace("%%", source);
+    let source = match meta.prelude {
+        Some(ref prelude) => insert_prelude(&source, prelude),
+        None => source
+    };
+
+    info!("part_mani: {:?}", part_mani);
+    info!("source: {:?}", source);
+
+    let part_mani = try!(toml::Parser::new(&part_mani).parse()
+        .ok_or("could not parse embedded manifest"));
+    info!("part_mani: {:?}", part_mani);
+
+    // The package lives in the cache, on its own, so a workspace can't possibly work.  Additional binaries are fine; a library isn't something we can run.
+    if part_mani.contains_key("workspace") {
+        try!(Err((Blame::Human, "scripts cannot declare a `[workspace]`")));
+    }
+    if part_mani.contains_key("lib") && !settings.quiet {
+        warn!("`[lib]` in a script's manifest is not supported; use --crate-type to build a script \
+            as a library");
+    }
+
+    // It's-a mergin' time!
+    let def_mani = try!(default_manifest(input, meta));
+    let dep_mani = try!(deps_manifest(&meta.deps));
+
+    let mani = try!(merge_manifest(def_mani, part_mani));
+    let mani = match meta.deps_inline {
+        Some(ref inline) => {
+            let inline_mani = try!(parse_user_manifest("--deps-inline", inline));
+            try!(merge_manifest(mani, inline_mani))
+        },
+        None => mani
+    };
+    let mani = try!(merge_manifest(mani, dep_mani));
+    let mani = match meta.dep_overrides.len() {
+        0 => mani,
+        _ => try!(merge_manifest(mani, try!(patch_manifest(&meta.dep_overrides))))
+    };
+    info!("mani: {:?}", mani);
+
+    let mani_str = format!("{}", toml::Value::Table(mani));
+    info!("mani_str: {}", mani_str);
+
+    Ok((mani_str, source))
+}
+
+/**
+Inserts the prelude at the top of the source.  Because inner attributes have to come before any items, the prelude goes *after* any leading ones.
+*/
+fn insert_prelude(source: &str, prelude: &str) -> String {
+    let mut offset = 0;
+    let mut cur = 0;
+    for line in source.split('\n') {
+        let line_trimmed = line.trim();
+        cur += line.len() + 1;
+        if line_trimmed.starts_with("#![") {
+            offset = ::std::cmp::min(cur, source.len());
+        } else if !(line_trimmed == "" || line_trimmed.starts_with("//")) {
+            break;
+        }
+    }
+
+    let (head, tail) = source.split_at(offset);
+    let mut source = String::with_capacity(source.len() + prelude.len() + 2);
+    source.push_str(head);
+    if head.len() > 0 && !head.ends_with("\n") {
+        source.push_str("\n");
+    }
+    source.push_str(prelude);
+    source.push_str("\n");
+    source.push_str(tail);
+    source
+}
+
+/**
+The result of splitting a script file into its embedded manifest and Rust source.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct SplitScript<'a> {
+    /// The embedded manifest.  This will be empty (or just a hashbang) if there isn't one.
+    pub manifest: Cow<'a, str>,
+
+    /// The Rust source.
+    pub source: &'a str,
+
+    /// What was used to decide where to split.
+    pub kind: SplitKind,
+}
+
+/**
+What was used to decide where the embedded manifest ends and the Rust source begins.
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum SplitKind {
+    /// A line consisting of whitespace and at least three hyphens.
+    Divider,
+    /// A line that looked like the start of some Rust code.
+    Marker,
+    /// A ```` ```cargo ```` fenced block in the script's leading comments.
+    Fence,
+    /// The end of the script's leading `//!` doc comments.
+    DocComment,
+}
+
+/**
+Splits the contents of a script file into the embedded manifest and the Rust source, without doing anything else to either.
+
+We *will not* assume the input is correctly formed, or that we've been passed a file that even *has* an embedded manifest; *i.e.* we might have been run with a plain Rust source file.
+
+If the script starts with line comments (doc or otherwise) containing a fenced block starting with ```` ```cargo ```` and ending with ```` ``` ````, that's the manifest, and everything after the closing fence is the source.  This is the *preferred* way of doing things, since it doesn't involve any guesswork.
+
+Failing that, if the script starts with `//!` doc comments which, with the `//!`s removed, contain a TOML table, that's the manifest.  This is *slightly* less reliable, since the comments might just happen to be valid TOML.
+
+Otherwise, we look for and discard a hashbang and a directive line (see `find_script_directive`), if present.
+
+Next, we look for something which indicates the end of the embedded manifest.  *Officially*, this is a line which contains nothing but whitespace and *at least* three hyphens.  In *truth*, we will also look for anything that looks like Rust code.
+
+Specifically, we check for a line starting with any of the strings in `SPLIT_MARKERS`.  This should *hopefully* cover every possible valid Rust program.
+
+Once we've done that, we just chop the script content up in the appropriate places.  Returns `None` if we couldn't find anywhere to split.
+
+A leading byte order mark is ignored, since it would otherwise hide whatever is on the first line.  It doesn't end up in either half.
+*/
+pub fn split_script<'a>(content: &'a str) -> Option<SplitScript<'a>> {
+    let content = match content.starts_with("\u{feff}") {
+        true => &content['\u{feff}'.len_utf8()..],
+        false => content
+    };
+
+    if let Some(split) = split_fenced_script(content) {
+        return Some(split);
+    }
+
+    if let Some(split) = split_doc_comment_script(content) {
+        return Some(split);
+    }
+
+    // Ok, it's-a guessin' time!  Yes, this is *evil*.
+    const SPLIT_MARKERS: &'static [&'static str] = &[
+        "//", "/*", "#![", "#[", "pub",
+        "extern", "use", "mod", "type",
+        "struct", "enum", "fn", "impl",
+        "static", "const",
+    ];
+
+    let mut lines = content.lines_any().peekable();
+
+    let skip = if let Some(line) = lines.peek() {
+        if line.starts_with("#!") && !line.starts_with("#![") {
+            // This is a hashbang; toss it.
+            true
+        } else {
+            false
+        }
+    } else {
+        false
+    };
+
+    if skip { lines.next(); }
+
+    // A directive line is neither manifest nor source, so the manifest starts after it.
+    let mut start = 0;
+    if lines.peek().map(|line| strip_script_directive(line).is_some()).unwrap_or(false) {
+        let line = lines.next().expect("directive line went missing");
+        start = content.subslice_offset(&line[line.len()..]);
+    }
+
+    for line in lines {
+        // Did we get a dash separator?
+        let mut dashes = 0;
+        if line.chars().all(|c| {
+            if c == '-' { dashes += 1 }
+            c.is_whitespace() || c == '-'
+        }) && dashes >= 3 {
+            info!("splitting because of dash divider in line {:?}", line);
+            return Some(SplitScript {
+                manifest: content[start..content.subslice_offset(&line[0..0])].into(),
+                source: &content[content.subslice_offset(&line[line.len()..])..],
+                kind: SplitKind::Divider,
+            });
+        }
+
+        let line_trimmed = line.trim_left();
+
+        for marker in SPLIT_MARKERS {
+            if line_trimmed.starts_with(marker) {
+                info!("splitting because of marker '{:?}'", marker);
+                let offset = content.subslice_offset(line);
+                return Some(SplitScript {
+                    manifest: content[start..offset].into(),
+                    source: &content[offset..],
+                    kind: SplitKind::Marker,
+                });
+            }
+        }
+    }
+
+    None
+}
+
+/**
+Splits a script whose manifest is in a ```` ```cargo ```` fenced block in its leading line comments.  Returns `None` if the leading comments don't contain one.
+*/
+fn split_fenced_script<'a>(content: &'a str) -> Option<SplitScript<'a>> {
+    let mut manifest = String::new();
+    let mut in_fence = false;
+
+    for (i, line) in content.lines_any().enumerate() {
+        if i == 0 && line.starts_with("#!") && !line.starts_with("#![") {
+            // Hashbang.
+            continue;
+        }
+
+        let line_trimmed = line.trim_left();
+        if !line_trimmed.starts_with("//") {
+            if !in_fence && line_trimmed == "" {
+                continue;
+            }
+            // Either we've run out of comments, or the fence was never closed.
+            return None;
+        }
+
+        // Strip the comment marker (including doc comment markers), and the single space that usually follows it.
+        let text = line_trimmed[2..].trim_left_matches(|c| c == '/' || c == '!');
+        let text = if text.starts_with(" ") { &text[1..] } else { text };
+
+        match (in_fence, text.trim_right()) {
+            (false, "```cargo") => in_fence = true,
+            (false, _) => (),
+            (true, "```") => {
+                info!("splitting because of fenced manifest ending in line {:?}", line);
+                return Some(SplitScript {
+                    manifest: manifest.into(),
+                    source: &content[content.subslice_offset(&line[line.len()..])..],
+                    kind: SplitKind::Fence,
+                });
+            },
+            (true, _) => {
+                manifest.push_str(text);
+                manifest.push_str("\n");
+            }
+        }
+    }
+
+    None
+}
+
+/**
+Splits a script whose manifest is the `//!` doc comments at its start, after any hashbang or directive line.  Returns `None` if there aren't any, or if they don't contain a TOML table, since then they're most likely just the script's documentation.
+*/
+fn split_doc_comment_script<'a>(content: &'a str) -> Option<SplitScript<'a>> {
+    let mut manifest = String::new();
+    let mut lines = content.lines_any().peekable();
+
+    if lines.peek().map(|line| line.starts_with("#!") && !line.starts_with("#![")).unwrap_or(false) {
+        lines.next();
+    }
+    if lines.peek().map(|line| strip_script_directive(line).is_some()).unwrap_or(false) {
+        lines.next();
+    }
+
+    for line in lines {
+        if !line.starts_with("//!") {
+            match toml::Parser::new(&manifest).parse() {
+                Some(ref table) if !table.is_empty() => (),
+                _ => return None
+            }
+            info!("splitting because of the end of doc comments in line {:?}", line);
+            return Some(SplitScript {
+                manifest: manifest.into(),
+                source: &content[content.subslice_offset(line)..],
+                kind: SplitKind::DocComment,
+            });
+        }
+
+        // Strip the doc comment marker, and the single space that usually follows it.
+        let text = &line[3..];
+        let text = if text.starts_with(" ") { &text[1..] } else { text };
+        manifest.push_str(text);
+        manifest.push_str("\n");
+    }
+
+    None
+}
+
+/**
+Generates a default Cargo manifest for the given input.
+*/
+fn default_manifest(input: &Input, meta: &PackageMetadata) -> Result<toml::Table> {
+    let mani_str = match meta.crate_type {
+        Some(ref crate_type) => consts::DEFAULT_LIB_MANIFEST
+            .replace("%l", &input.safe_name().replace("-", "_"))
+            .replace("%t", crate_type),
+        None => consts::DEFAULT_MANIFEST.into()
+    };
+    let mani_str = mani_str.replace("%n", &input.safe_name());
+    let mut mani = try!(toml::Parser::new(&mani_str).parse()
+        .ok_or("could not parse default manifest, somehow"));
+
+    // This goes in the default manifest, so that an edition given in the script's own manifest still wins.
+    if let Some(ref edition) = meta.edition {
+        if let Some(&mut toml::Value::Table(ref mut package)) = mani.get_mut("package") {
+            package.insert("edition".into(), toml::Value::String(edition.clone()));
+        }
+    }
+
+    Ok(mani)
+}
+
+/**
+Generates a partial Cargo manifest containing the specified dependencies.
+*/
+pub fn deps_manifest(deps: &[(String, String)]) -> Result<toml::Table> {
+    dep_list_manifest("dependencies", deps)
+}
+
+/**
+Generates a partial Cargo manifest which patches crates.io with the specified dependencies.
+*/
+fn patch_manifest(overrides: &[(String, String)]) -> Result<toml::Table> {
+    dep_list_manifest("patch.crates-io", overrides)
+}
+
+/**
+Generates a partial Cargo manifest with a single table of dependency specs.
+*/
+fn dep_list_manifest(table: &str, deps: &[(String, String)]) -> Result<toml::Table> {
+    let mut mani_str = String::new();
+    mani_str.push_str("[");
+    mani_str.push_str(table);
+    mani_str.push_str("]\n");
+
+    for &(ref name, ref ver) in deps {
+        mani_str.push_str(name);
+        mani_str.push_str("=");
+
+        // We only want to quote the version if it *isn't* a table.
+        let quotes = match ver.starts_with("{") { true => "", false => "\"" };
+        mani_str.push_str(quotes);
+        mani_str.push_str(ver);
+        mani_str.push_str(quotes);
+        mani_str.push_str("\n");
+    }
+
+    toml::Parser::new(&mani_str).parse()
+        .ok_or(format!("could not parse [{}] manifest", table).into())
+}
+
+/**
+Parses a manifest fragment given to us by the user.  `what` is used to tell them where the broken fragment came from.
+*/
+pub fn parse_user_manifest(what: &str, mani_str: &str) -> Result<toml::Table> {
+    let mut parser = toml::Parser::new(mani_str);
+    match parser.parse() {
+        Some(mani) => Ok(mani),
+        None => {
+            let msg = match parser.errors.first() {
+                Some(err) => {
+                    let (line, col) = parser.to_linecol(err.lo);
+                    format!("could not parse {}: {} at line {}, column {}",
+                        what, err.desc, line + 1, col + 1)
+                },
+                None => format!("could not parse {}", what)
+            };
+            Err((Blame::Human, msg).into())
+        }
+    }
+}
+
+/**
+Given two Cargo manifests, merges the second *into* the first.
+
+Tables are merged recursively, so that nested sections like `[profile.release]` or `[dependencies.foo]` can be added to piecemeal.  Everything else (including arrays) is just outright replaced, as is anything a table is merged over which isn't itself a table.
+*/
+fn merge_manifest(mut into_t: toml::Table, from_t: toml::Table) -> Result<toml::Table> {
+    for (k, v) in from_t {
+        match v {
+            toml::Value::Table(from_t) => {
+                use std::collections::btree_map::Entry::*;
+
+                // Merge.
+                match into_t.entry(k) {
+                    Vacant(e) => {
+                        e.insert(toml::Value::Table(from_t));
+                    },
+                    Occupied(e) => {
+                        // A table replaces anything that isn't one, such as a dependency going from a version to a detailed table.
+                        let into_v = e.into_mut();
+                        *into_v = match ::std::mem::replace(into_v, toml::Value::Boolean(false)) {
+                            toml::Value::Table(old_t) => toml::Value::Table(try!(merge_manifest(old_t, from_t))),
+                            _ => toml::Value::Table(from_t)
+                        };
+                    }
+                }
+            },
+            v => {
+                // Just replace.
+                into_t.insert(k, v);
+            },
+        }
+    }
+
+    Ok(into_t)
+}
+
+/**
+Settings which affect *how* a package is built, but not *what* gets built.  As such, they don't belong in `PackageMetadata`.
+*/
+#[derive(Clone, Debug)]
+pub struct BuildSettings {
+    /// Check that the toolchain supports the script's edition before building?
+    pub check_edition: bool,
+
+    /// Most jobs Cargo should run at once.
+    pub jobs: Option<u32>,
+
+    /// Whether Cargo should colour its output.
+    pub color: Color,
+
+    /// Pass `--quiet` to Cargo?
+    pub quiet_cargo: bool,
+
+    /// Keep our own warnings and notes to ourselves?
+    pub quiet: bool,
+
+    /// Stop Cargo from touching the network?
+    pub offline: bool,
+
+    /// Record the package's metadata once it's built?  Not with `--no-cache`, since the package is thrown away.
+    pub write_metadata: bool,
+}
+
+impl Default for BuildSettings {
+    fn default() -> BuildSettings {
+        BuildSettings {
+            check_edition: true,
+            jobs: None,
+            color: Color::Auto,
+            quiet_cargo: false,
+            quiet: false,
+            offline: false,
+            write_metadata: true,
+        }
+    }
+}
+
+/**
+This represents what to do with the input provided by the user.
+*/
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum CacheAction {
+    /// Compile the input into a fresh executable.
+    Compile,
+    /// Don't bother compiling; just execute what's in the cache.
+    Execute,
+}
+
+/**
+The metadata here serves two purposes:
+
+1. It records everything necessary for compilation and execution of a package.
+2. It records everything that must be exactly the same in order for a cached executable to still be valid, in addition to the content hash.
+*/
+#[derive(Clone, Debug, Default, Eq, PartialEq, RustcDecodable, RustcEncodable)]
+pub struct PackageMetadata {
+    /// Path to the script file.
+    pub path: Option<String>,
+
+    /// Last-modified timestamp for script file.
+    pub modified: Option<u64>,
+
+    /// Was the script compiled in debug mode?
+    pub debug: bool,
+
+    /// Sorted list of dependencies.
+    pub deps: Vec<(String, String)>,
+
+    /// Sorted list of dependency versions to force with `[patch.crates-io]`.
+    pub dep_overrides: Vec<(String, String)>,
+
+    /// Manifest fragment given with `--deps-inline`.
+    pub deps_inline: Option<String>,
+
+    /// Contents of the prelude file, if any.
+    pub prelude: Option<String>,
+
+    /// Contents of the user's template for `--expr` or `--loop` input, if any.
+    pub template: Option<String>,
+
+    /// Sanitizer to build with, if any.
+    pub sanitizer: Option<String>,
+
+    /// Target triple to build for, if not the default.
+    pub target: Option<String>,
+
+    /// Target features to enable or disable.
+    pub target_features: Option<String>,
+
+    /// Sorted list of features to enable.
+    pub features: Vec<String>,
+
+    /// Feature to enable on every dependency that has it.
+    pub features_all_deps: Option<String>,
+
+    /// Kind of library to build instead of an executable, if any.
+    pub crate_type: Option<String>,
+
+    /// Binary to build and run, if given with `--bin`.
+    pub bin: Option<String>,
+
+    /// Rust edition to build with, if given with `--edition`.
+    pub edition: Option<String>,
+
+    /// Was the package built with `--reproducible-build`?
+    pub reproducible: bool,
+
+    /// Rustup toolchain to build with, if not the default.
+    pub toolchain: Option<String>,
+
+    /// Extra flags to pass to `rustc`, in the order given.
+    pub rustc_flags: Vec<String>,
+
+    /// Size of the executable when it was built.  Unlike the rest, this is filled in by `compile`, and ignored when comparing metadata.
+    pub exe_size: Option<u64>,
+
+    /// SHA-1 hash of the executable when it was built.  As with `exe_size`, this is filled in by `compile`.
+    pub exe_hash: Option<String>,
+
+    /// Where Cargo said it put the executable, if it did.  As with `exe_size`, this is filled in by `compile`.
+    pub exe_path: Option<String>,
+
+    /// SHA-1 hash of the package's `Cargo.lock` when it was built.  As with `exe_size`, this is filled in by `compile`.
+    ///
+    /// This only notices the package's own lockfile being changed after the build, such as by running `cargo update` in its cache folder.  Nothing re-resolves the dependencies on every run, so a newer version of a wildcard dependency turning up in the index doesn't count; picking that up takes `--clean`, which throws the old lockfile away along with the rest of the package.
+    pub lock_hash: Option<String>,
+}
+
+impl PackageMetadata {
+    /**
+    Returns the name of the Cargo profile the package is built with.
+    */
+    pub fn profile(&self) -> &'static str {
+        match self.debug {
+            true => "debug",
+            false => "release"
+        }
+    }
+
+    /**
+    Returns the rustup toolchain the package is built with, if not the default.
+    */
+    pub fn toolchain(&self) -> Option<&str> {
+        self.toolchain.as_ref().map(|s| &**s)
+    }
+}
+
+/**
+For the given input and its package metadata, this checks the cache to see what should be done.
+
+If `explain` is set, the reason for a recompile is written to stderr as JSON.  If `verify` is set, the executable's hash is checked against the one recorded when it was built; its size is always checked.
+
+Problems with what's *in* the cache just mean a recompile; this only fails if it can't work out where the package should be at all.  Nothing in the cache is changed, so this is safe to use just to find out what would happen.
+*/
+pub fn cache_action_for(input: &Input, mut input_meta: PackageMetadata, explain: bool, verify: bool) -> Result<(CacheAction, PathBuf, PackageMetadata)> {
+    use std::fs::PathExt;
+
+    let cache_path = try!(get_cache_path());
+    info!("cache_path: {:?}", cache_path);
+
+    let id = try!(input.compute_id(&input_meta));
+    info!("id: {:?}", id);
+
+    let pkg_path = cache_path.join(&id);
+    info!("pkg_path: {:?}", pkg_path);
+
+    // Lazy powers, ACTIVATE!
+    macro_rules! bail {
+        ($reason:expr) => { bail!($reason, None) };
+        ($reason:expr, $cache_meta:expr) => {
+            {
+                if explain {
+                    explain_cache_miss($reason, $cache_meta, &input_meta);
+                }
+                return Ok((CacheAction::Compile, pkg_path, input_meta))
+            }
+        }
+    }
+
+    let cache_meta = match get_pkg_metadata(&pkg_path) {
+        Ok(meta) => meta,
+        Err(err) => {
+            info!("recompiling because: failed to load metadata");
+            debug!("get_pkg_metadata error: {}", err.description());
+            bail!("failed to load metadata")
+        }
+    };
+
+    // These describe the executable, not the input, so they don't take part in the comparison.
+    let (exe_size, exe_hash) = (cache_meta.exe_size, cache_meta.exe_hash.clone());
+    let (exe_path, lock_hash) = (cache_meta.exe_path.clone(), cache_meta.lock_hash.clone());
+    let cache_meta = PackageMetadata { exe_size: None, exe_hash: None, exe_path: None, lock_hash: None, ..cache_meta };
+
+    if cache_meta != input_meta {
+        info!("recompiling because: metadata did not match");
+        debug!("input metadata: {:?}", input_meta);
+        debug!("cache metadata: {:?}", cache_meta);
+        bail!("metadata did not match", Some(&cache_meta))
+    }
+
+    // From here on, we want to look for the executable wherever Cargo said it was.
+    input_meta.exe_path = exe_path;
+
+    // Next test: does the executable exist at all?  If the script builds several, they all have to.
+    let exe_paths = match get_artifact_paths(input, &pkg_path, &input_meta) {
+        Ok(exe_paths) => exe_paths,
+        Err(err) => {
+            info!("recompiling because: couldn't work out the executable path");
+            debug!("get_artifact_paths error: {}", err);
+            bail!("couldn't work out the executable path")
+        }
+    };
+    if !exe_paths.iter().all(|exe_path| exe_path.is_file()) {
+        info!("recompiling because: executable doesn't exist or isn't a file");
+        bail!("executable doesn't exist or isn't a file")
+    }
+
+    // Make sure it hasn't been truncated or otherwise mangled since it was built.  This is only recorded when there's just the one executable.
+    let exe_path = match (exe_paths.len(), exe_paths.into_iter().next()) {
+        (1, Some(exe_path)) => Some(exe_path),
+        _ => None
+    };
+    let size = exe_path.as_ref().and_then(|exe_path| fs::metadata(exe_path).map(|md| md.len()).ok());
+    if exe_size.is_some() && size != exe_size {
+        info!("recompiling because: executable size doesn't match");
+        debug!("expected {:?}, found {:?}", exe_size, size);
+        bail!("executable size doesn't match")
+    }
+
+    // If the package's dependencies have been re-resolved since (say, by `cargo update` in the cache folder), the executable is out of date.  We don't resolve them again ourselves; see `PackageMetadata::lock_hash`.
+    if lock_hash.is_some() {
+        let hash = hash_file(pkg_path.join(consts::CARGO_LOCK_FILE)).ok();
+        if hash != lock_hash {
+            info!("recompiling because: Cargo.lock has changed");
+            debug!("expected {:?}, found {:?}", lock_hash, hash);
+            bail!("Cargo.lock has changed")
+        }
+    }
+
+    if let (true, Some(exe_path)) = (verify, exe_path) {
+        let hash = hash_file(&exe_path).ok();
+        if exe_hash.is_none() || hash != exe_hash {
+            info!("recompiling because: executable hash doesn't match");
+            debug!("expected {:?}, found {:?}", exe_hash, hash);
+            bail!("executable hash doesn't match")
+        }
+    }
+
+    // That's enough; let's just go with it.
+    Ok((CacheAction::Execute, pkg_path, input_meta))
+}
+
+/**
+Writes a JSON explanation of why a recompile is happening to stderr.
+
+If there was cached metadata to compare against, this includes every field which differs, along with its cached and current values.
+*/
+fn explain_cache_miss(reason: &str, cache_meta: Option<&PackageMetadata>, input_meta: &PackageMetadata) {
+    let explanation = cache_miss_explanation(reason, cache_meta, input_meta);
+    let _ = writeln!(std::io::stderr(), "{}", explanation);
+}
+
+/**
+Builds the explanation written out by `explain_cache_miss`.
+*/
+fn cache_miss_explanation(reason: &str, cache_meta: Option<&PackageMetadata>, input_meta: &PackageMetadata) -> rustc_serialize::json::Json {
+    use std::collections::BTreeMap;
+    use rustc_serialize::json::{self, Json};
+
+    fn to_json(meta: &PackageMetadata) -> Option<Json> {
+        json::encode(meta).ok().and_then(|s| Json::from_str(&s).ok())
+    }
+
+    let mut explanation = BTreeMap::new();
+    explanation.insert("reason".to_owned(), Json::String(reason.into()));
+
+    if let Some(cache_meta) = cache_meta {
+        let mut changed = BTreeMap::new();
+        if let (Some(Json::Object(cached)), Some(Json::Object(current)))
+            = (to_json(cache_meta), to_json(input_meta))
+        {
+            for (field, current_value) in current {
+                let cached_value = cached.get(&field).cloned().unwrap_or(Json::Null);
+                if cached_value != current_value {
+                    let mut diff = BTreeMap::new();
+                    diff.insert("cached".to_owned(), cached_value);
+                    diff.insert("current".to_owned(), current_value);
+                    changed.insert(field, Json::Object(diff));
+                }
+            }
+        }
+        explanation.insert("changed".to_owned(), Json::Object(changed));
+    }
+
+    Json::Object(explanation)
+}
+
+/**
+Figures out where the output executable for the input should be.
+
+If Cargo told us where it put the executable when it was built, that's where it is.  Otherwise, this depends on Cargo *not* suddenly changing its mind about where stuff lives.
+*/
+pub fn get_exe_path<P>(input: &Input, pkg_path: P, meta: &PackageMetadata) -> Result<PathBuf>
+where P: AsRef<Path> {
+    if let Some(ref exe_path) = meta.exe_path {
+        return Ok(exe_path.into());
+    }
+
+    let pkg_path = pkg_path.as_ref();
+    let bin = try!(get_default_bin(input, pkg_path, meta));
+    Ok(get_bin_path(pkg_path, meta, &bin))
+}
+
+/**
+Works out which binary to run, given the path to the package's cache folder.  See `find_default_bin`; this fails if there isn't just the one.
+*/
+fn get_default_bin<P>(input: &Input, pkg_path: P, meta: &PackageMetadata) -> Result<String>
+where P: AsRef<Path> {
+    let mani = try!(get_pkg_manifest(pkg_path));
+    match try!(find_default_bin(input, &mani, meta)) {
+        Some(bin) => Ok(bin),
+        None => Err((Blame::Human,
+            format!("script declares more than one binary ({}); use --bin, or set \
+                `default-run` in its [package] section, to pick one",
+                manifest_bin_names(&mani).join(", "))).into())
+    }
+}
+
+/**
+Works out which binary to run, given the package's manifest.
+
+If `--bin` was given, that's the one, provided the manifest actually declares it.  Otherwise, if the manifest declares more than one binary, `package.default-run` picks which; otherwise, it's whichever one there is.  Returns `None` if there are several, and nothing to pick between them.
+*/
+fn find_default_bin(input: &Input, mani: &toml::Table, meta: &PackageMetadata) -> Result<Option<String>> {
+    let mut bins = manifest_bin_names(mani);
+
+    if let Some(ref bin) = meta.bin {
+        if !bins.contains(bin) {
+            try!(Err((Blame::Human,
+                format!("no binary named '{}'; the script declares: {}", bin, bins.join(", ")))));
+        }
+        return Ok(Some(bin.clone()));
+    }
+
+    if bins.len() <= 1 {
+        return Ok(Some(bins.pop()
+            .or_else(|| manifest_pkg_name(mani).map(String::from))
+            .unwrap_or_else(|| input.safe_name())));
+    }
+
+    let default_run = mani.get("package")
+        .and_then(|p| p.lookup("default-run"))
+        .and_then(|v| v.as_str())
+        .map(String::from);
+
+    match default_run {
+        Some(ref bin) if bins.contains(bin) => Ok(Some(bin.clone())),
+        Some(bin) => Err((Blame::Human,
+            format!("`default-run` names '{}', but the script only declares: {}",
+                bin, bins.join(", "))).into()),
+        None => Ok(None)
+    }
+}
+
+/**
+Figures out where the executable for the named binary target should be.  This has the same caveats as `get_exe_path`.
+*/
+pub fn get_bin_path<P>(pkg_path: P, meta: &PackageMetadata, bin: &str) -> PathBuf
+where P: AsRef<Path> {
+    let mut exe_path = get_out_dir(pkg_path, meta).join(bin).into_os_string();
+    exe_path.push(std::env::consts::EXE_SUFFIX);
+    exe_path.into()
+}
+
+/**
+Figures out where whatever the package builds should be: the library if it's built with `--crate-type`, otherwise the executable.
+*/
+fn get_artifact_path<P>(input: &Input, pkg_path: P, meta: &PackageMetadata) -> Result<PathBuf>
+where P: AsRef<Path> {
+    match meta.crate_type {
+        Some(ref crate_type) => Ok(get_lib_path(input, pkg_path, meta, crate_type)),
+        None => get_exe_path(input, pkg_path, meta)
+    }
+}
+
+/**
+Figures out where everything the package builds should be.  That's usually just the one thing (see `get_artifact_path`), but a script which declares several binaries, without saying which to run, builds all of them.
+*/
+fn get_artifact_paths<P>(input: &Input, pkg_path: P, meta: &PackageMetadata) -> Result<Vec<PathBuf>>
+where P: AsRef<Path> {
+    let pkg_path = pkg_path.as_ref();
+    if meta.crate_type.is_some() || meta.exe_path.is_some() {
+        return get_artifact_path(input, pkg_path, meta).map(|path| vec![path]);
+    }
+
+    let mani = try!(get_pkg_manifest(pkg_path));
+    match try!(find_default_bin(input, &mani, meta)) {
+        Some(bin) => Ok(vec![get_bin_path(pkg_path, meta, &bin)]),
+        None => Ok(manifest_bin_names(&mani).iter()
+            .map(|bin| get_bin_path(pkg_path, meta, bin))
+            .collect())
+    }
+}
+
+/**
+Computes the SHA-1 hash of a file's contents, as a hex string.
+*/
+fn hash_file<P>(path: P) -> Result<String>
+where P: AsRef<Path> {
+    use shaman::digest::Digest;
+    use shaman::sha1::Sha1;
+
+    let mut hasher = Sha1::new();
+    let mut file = try!(fs::File::open(path));
+    let mut buf = [0u8; 64 * 1024];
+    loop {
+        match try!(file.read(&mut buf)) {
+            0 => break,
+            n => hasher.input(&buf[..n])
+        }
+    }
+    Ok(hasher.result_str())
+}
+
+/**
+Figures out where the library built from the input should be, for when it's built with `--crate-type`.
+*/
+pub fn get_lib_path<P>(input: &Input, pkg_path: P, meta: &PackageMetadata, crate_type: &str) -> PathBuf
+where P: AsRef<Path> {
+    use std::env::consts::{DLL_PREFIX, DLL_SUFFIX};
+
+    let name = input.safe_name().replace("-", "_");
+    let file_name = match crate_type {
+        "staticlib" if cfg!(windows) => format!("{}.lib", name),
+        "staticlib" => format!("lib{}.a", name),
+        _ => format!("{}{}{}", DLL_PREFIX, name, DLL_SUFFIX)
+    };
+    get_out_dir(pkg_path, meta).join(file_name)
+}
+
+/**
+Returns the directory Cargo puts build products in for the package in the given cache folder.
+*/
+fn get_out_dir<P>(pkg_path: P, meta: &PackageMetadata) -> PathBuf
+where P: AsRef<Path> {
+    let mut out_dir = pkg_path.as_ref().join("target");
+    if let Some(ref target) = meta.target {
+        out_dir = out_dir.join(target);
+    }
+    out_dir.join(meta.profile())
+}
+
+/**
+Load the package metadata, given the path to the package's cache folder.
+*/
+pub fn get_pkg_metadata<P>(pkg_path: P) -> Result<PackageMetadata>
+where P: AsRef<Path> {
+    let meta_path = pkg_path.as_ref().join(consts::METADATA_FILE);
+    debug!("meta_path: {:?}", meta_path);
+    let mut meta_file = try!(fs::File::open(&meta_path));
+
+    let meta_str = {
+        let mut s = String::new();
+        try!(meta_file.read_to_string(&mut s));
+        s
+    };
+    let meta: PackageMetadata = try!(rustc_serialize::json::decode(&meta_str)
+        .map_err(|err| err.to_string()));
+
+    Ok(meta)
+}
+
+/**
+Save the package metadata, given the path to the package's cache folder.
+*/
+fn write_pkg_metadata<P>(pkg_path: P, meta: &PackageMetadata) -> Result<()>
+where P: AsRef<Path> {
+    let meta_path = pkg_path.as_ref().join(consts::METADATA_FILE);
+    debug!("meta_path: {:?}", meta_path);
+    let mut meta_file = try!(fs::File::create(&meta_path));
+    let meta_str = try!(rustc_serialize::json::encode(meta)
+        .map_err(|err| err.to_string()));
+    try!(write!(&mut meta_file, "{}", meta_str));
+    try!(meta_file.flush());
+    Ok(())
+}
+
+/**
+Returns the ID and path of every package in the cache.  Anything in there that isn't a directory is ignored.
+*/
+pub fn get_cache_packages(cache_path: &Path) -> Result<Vec<(String, PathBuf)>> {
+    use std::fs::PathExt;
+
+    let mut pkgs = vec![];
+    if !cache_path.is_dir() {
+        return Ok(pkgs);
+    }
+
+    for entry in try!(fs::read_dir(cache_path)) {
+        let entry = try!(entry);
+        let path = entry.path();
+        if path.is_dir() {
+            pkgs.push((entry.file_name().to_string_lossy().into_owned(), path));
+        }
+    }
+    pkgs.sort();
+    Ok(pkgs)
+}
+
+/**
+Cleans up the cache.  This removes packages which haven't been built in `max_age` milliseconds, along with the packages for script files which no longer exist, since nothing can ever use them again.
+
+Packages for stdin, expressions, and loops have no path, so only their age counts.  Packages whose metadata can't be read are left alone, since we can't tell where they came from.
+
+This is strictly best-effort: if a package can't be looked at or removed, that's logged and the rest are still cleaned.  An error is only returned if the cache folder itself can't be read or written.
+*/
+pub fn clean_cache(max_age: u64) -> Result<()> {
+    let cache_path = try!(get_cache_path());
+    let cutoff = cache_cutoff(try!(current_time(&cache_path)), max_age);
+    info!("cutoff: {:?}", cutoff);
+    clean_cache_packages(&cache_path, cutoff)
+}
+
+/**
+Cleans each of the packages in the given cache folder.  See `clean_cache`.
+*/
+fn clean_cache_packages(cache_path: &Path, cutoff: u64) -> Result<()> {
+    for (id, pkg_path) in try!(get_cache_packages(cache_path)) {
+        if let Err(err) = clean_cache_package(&id, &pkg_path, cutoff) {
+            warn!("could not clean {:?}: {}", id, err);
+        }
+    }
+    Ok(())
+}
+
+/**
+Works out the time before which packages are old enough to be removed.  If `max_age` goes back further than the clock does, that's the beginning of time, rather than wrapping around to some time in the far future.
+*/
+pub fn cache_cutoff(now: u64, max_age: u64) -> u64 {
+    now.saturating_sub(max_age)
+}
+
+/**
+Removes a single package from the cache if it was last built before `cutoff`, or if the script it was built from no longer exists.
+*/
+fn clean_cache_package(id: &str, pkg_path: &Path, cutoff: u64) -> Result<()> {
+    use std::fs::PathExt;
+
+    let mtime = match fs::metadata(pkg_path.join(consts::METADATA_FILE)) {
+        Ok(md) => md.modified(),
+        Err(err) => {
+            info!("not cleaning {:?}: {}", id, err);
+            return Ok(());
+        }
+    };
+
+    if mtime < cutoff {
+        info!("removing stale package {:?}", id);
+        try!(fs::remove_dir_all(pkg_path));
+        return Ok(());
+    }
+
+    let meta = match get_pkg_metadata(pkg_path) {
+        Ok(meta) => meta,
+        Err(err) => {
+            info!("not cleaning {:?}: {}", id, err);
+            return Ok(());
+        }
+    };
+
+    if let Some(ref script_path) = meta.path {
+        if !Path::new(script_path).exists() {
+            info!("removing orphaned package {:?} (for {:?})", id, script_path);
+            try!(fs::remove_dir_all(pkg_path));
+        }
+    }
+    Ok(())
+}
+
+/**
+Gets the current time, in the same terms as file modification times.
+
+There's no stable way to ask the standard library what time it is, so this cheats: it touches a file in the cache folder, and reads back *its* modification time.
+*/
+pub fn current_time(cache_path: &Path) -> Result<u64> {
+    try!(fs::create_dir_all(cache_path));
+    let stamp_file = try!(fs::File::create(cache_path.join(consts::TIMESTAMP_FILE)));
+    let md = try!(stamp_file.metadata());
+    Ok(md.modified())
+}
+
+/**
+Returns the path to the config directory.
+*/
+pub fn get_config_path() -> Result<PathBuf> {
+    let config_path = try!(platform::get_config_dir_for("Cargo"));
+    Ok(config_path.join("script-config"))
+}
+
+/**
+Returns the path to the cache directory.
+
+This can be overridden with `CARGO_SCRIPT_CACHE_DIR`.  Either way, the directory might not exist yet; it gets created along with the first package put in it.
+*/
+pub fn get_cache_path() -> Result<PathBuf> {
+    match std::env::var(consts::CACHE_DIR_ENV) {
+        Ok(ref cache_path) if cache_path != "" => {
+            return Ok(try!(std::env::current_dir()).join(cache_path));
+        },
+        _ => ()
+    }
+
+    let cache_path = try!(platform::get_cache_dir_for("Cargo"));
+    Ok(cache_path.join("script-cache"))
+}
+
+/**
+Represents an input source for a script.
+*/
+#[derive(Clone, Debug)]
+pub enum Input<'a> {
+    /**
+    The input is a script file.
+
+    The tuple members are: the name, absolute path, script contents, last modified time.
+    */
+    File(&'a str, &'a Path, &'a str, u64),
+
+    /**
+    The input is a script read from stdin.
+
+    The tuple member is: the script contents.
+    */
+    Stdin(&'a str),
+
+    /**
+    The input is an expression.
+
+    The tuple member is: the script contents, how the result should be displayed.
+    */
+    Expr(&'a str, ExprStyle),
+
+    /**
+    The input is a loop expression.
+
+    The tuple member is: the script contents, whether the `--count` flag was given, the byte stdin is split on.
+    */
+    Loop(&'a str, bool, u8),
+}
+
+/**
+How the result of an `--expr` should be displayed.
+*/
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ExprStyle {
+    /// Using its `Debug` output.
+    Debug,
+    /// Using its pretty `Debug` output, from `--pretty`.
+    Pretty,
+    /// As JSON, from `--json`.
+    Json,
+}
+
+impl<'a> Input<'a> {
+    /**
+    Return the "safe name" for the input.  This is filename-safe, and is used as the package name, the binary name, *and* the name of the source file, so they all have to agree.
+
+    Anything Cargo wouldn't accept in a package name gets replaced with an underscore, as does a leading digit.
+    */
+    pub fn safe_name(&self) -> String {
+        use Input::*;
+
+        let name = match *self {
+            File(name, _, _, _) => name,
+            Stdin(..) => "stdin",
+            Expr(..) => "expr",
+            Loop(..) => "loop",
+        };
+
+        let mut safe_name = String::with_capacity(name.len());
+        for (i, c) in name.chars().enumerate() {
+            let ok = match c {
+                'a'...'z' | 'A'...'Z' | '_' | '-' => true,
+                '0'...'9' => i != 0,
+                _ => false
+            };
+            safe_name.push(if ok { c } else { '_' });
+        }
+
+        if safe_name.is_empty() {
+            safe_name.push_str("script");
+        }
+
+        safe_name
+    }
+
+    /**
+    Returns the built-in template the input is wrapped in, before any user template given with `--template` is taken into account.
+    */
+    pub fn template(&self) -> &'static str {
+        use Input::*;
+
+        match *self {
+            File(..) | Stdin(..) => consts::FILE_TEMPLATE,
+            Expr(_, ExprStyle::Debug) => consts::EXPR_TEMPLATE,
+            Expr(_, ExprStyle::Pretty) => consts::EXPR_PRETTY_TEMPLATE,
+            Expr(_, ExprStyle::Json) => consts::EXPR_JSON_TEMPLATE,
+            Loop(_, false, _) => consts::LOOP_TEMPLATE,
+            Loop(_, true, _) => consts::LOOP_COUNT_TEMPLATE,
+        }
+    }
+
+    /**
+    Compute the package ID for the input.  This is used as the name of the cache folder into which the Cargo package will be generated.
+
+    Aside from the input itself, this takes into account anything in the package metadata that changes the compiled output in a way that shouldn't share a cache folder.
+    */
+    pub fn compute_id(&self, meta: &PackageMetadata) -> Result<OsString> {
+        use flate2::FlateWriteExt;
+        use shaman::digest::Digest;
+        use shaman::sha1::Sha1;
+        use Input::*;
+
+        // Hash all the deps now.
+        let mut hasher = Sha1::new();
+        for &(ref name, ref version) in &meta.deps {
+            hash_field(&mut hasher, "dep", Some(format!("{}={}", name, version)));
+        }
+
+        // Everything else is only hashed if it's present, so that IDs for plain builds don't change.
+        for &(ref name, ref version) in &meta.dep_overrides {
+            hash_field(&mut hasher, "dep-override", Some(format!("{}={}", name, version)));
+        }
+        hash_field(&mut hasher, "deps-inline", meta.deps_inline.as_ref());
+        hash_field(&mut hasher, "prelude", meta.prelude.as_ref());
+        hash_field(&mut hasher, "template", meta.template.as_ref());
+
+        // Without a user template, expressions and loops are wrapped in a built-in one.  If that changes between versions, anything built with the old one is stale.
+        let builtin_template = match (self, &meta.template) {
+            (&Expr(..), &None) | (&Loop(..), &None) => Some(self.template()),
+            _ => None
+        };
+        hash_field(&mut hasher, "builtin-template", builtin_template);
+
+        hash_field(&mut hasher, "sanitizer", meta.sanitizer.as_ref());
+        hash_field(&mut hasher, "target", meta.target.as_ref());
+        hash_field(&mut hasher, "target-feature", meta.target_features.as_ref());
+        hash_field(&mut hasher, "features-all-deps", meta.features_all_deps.as_ref());
+        hash_field(&mut hasher, "crate-type", meta.crate_type.as_ref());
+        hash_field(&mut hasher, "edition", meta.edition.as_ref());
+
+        if meta.reproducible {
+            hasher.input_str("reproducible;");
+        }
+
+        hash_field(&mut hasher, "toolchain", meta.toolchain.as_ref());
+        for feature in &meta.features {
+            hash_field(&mut hasher, "feature", Some(feature));
+        }
+        for flag in &meta.rustc_flags {
+            hash_field(&mut hasher, "rustc-flag", Some(flag));
+        }
+
+        match *self {
+            File(name, path, content, _) => {
+                // Deflate-compress the path to the script.
+                let z_path = {
+                    let buf: Vec<u8> = vec![];
+                    let hex = util::Hexify(buf);
+                    let mut z = hex.deflate_encode(flate2::Compression::Best);
+
+                    // Windows can spell the same path with either slash, so stick to one.
+                    let norm_path = match cfg!(windows) {
+                        true => path.to_string_lossy().replace("\\", "/"),
+                        false => path.to_string_lossy().into_owned()
+                    };
+                    try!(write!(z, "{}", norm_path));
+                    let mut buf = try!(z.finish()).0;
+
+                    buf.truncate(consts::DEFLATE_PATH_LEN_MAX);
+                    try!(String::from_utf8(buf)
+                        .map_err(|_| "could not UTF-8 encode deflated path"))
+                };
+
+                // Update the hash with the content.
+                hasher.input_str(&content);
+                let mut digest = hasher.result_str();
+                digest.truncate(consts::CONTENT_DIGEST_LEN_MAX);
+
+                let mut id = OsString::new();
+                id.push("file-");
+                id.push(name);
+                id.push("-");
+                id.push(if STUB_HASHES { "stub" } else { &*z_path });
+                id.push("-");
+                id.push(if STUB_HASHES { "stub" } else { &*digest });
+                Ok(id)
+            },
+            Stdin(content) => {
+                // There's no path to go on, so this is just like an expression.
+                hasher.input_str(&content);
+                let mut digest = hasher.result_str();
+                digest.truncate(consts::CONTENT_DIGEST_LEN_MAX);
+
+                let mut id = OsString::new();
+                id.push("stdin-");
+                id.push(if STUB_HASHES { "stub" } else { &*digest });
+                Ok(id)
+            },
+            Expr(content, style) => {
+                // A user template is shared by every style, so the style has to be hashed separately.  Anything but the default is enough to tell them apart.
+                match style {
+                    ExprStyle::Debug => (),
+                    ExprStyle::Pretty => hasher.input_str("pretty;"),
+                    ExprStyle::Json => hasher.input_str("json;"),
+                }
+
+                hasher.input_str(&content);
+                let mut digest = hasher.result_str();
+                digest.truncate(consts::CONTENT_DIGEST_LEN_MAX);
+
+                let mut id = OsString::new();
+                id.push("expr-");
+                id.push(if STUB_HASHES { "stub" } else { &*digest });
+                Ok(id)
+            },
+            Loop(content, count, sep) => {
+                // Make sure to include the [non-]presence of the `--count` flag in the flag, since it changes the actual generated script output.
+                hasher.input_str("count:");
+                hasher.input_str(if count { "true;" } else { "false;" });
+
+                // Likewise the separator, which the template bakes into the source.  Anything but the default is enough to tell them apart.
+                if sep != b'\n' {
+                    hasher.input_str(&format!("split:{};", sep));
+                }
+
+                hasher.input_str(&content);
+                let mut digest = hasher.result_str();
+                digest.truncate(consts::CONTENT_DIGEST_LEN_MAX);
+
+                let mut id = OsString::new();
+                id.push("loop-");
+                id.push(if STUB_HASHES { "stub" } else { &*digest });
+                Ok(id)
+            },
+        }
+    }
+}
+
+/**
+Feeds a named field into a package ID hash, if it has a value.  The name and terminator keep one field from being mistaken for another.
+*/
+fn hash_field<S>(hasher: &mut shaman::sha1::Sha1, name: &str, value: Option<S>)
+where S: AsRef<str> {
+    use shaman::digest::Digest;
+
+    if let Some(value) = value {
+        hasher.input_str(name);
+        hasher.input_str("=");
+        hasher.input_str(value.as_ref());
+        hasher.input_str(";");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{BuildSettings, CacheAction, Color, ExprStyle, Input, PackageMetadata, Result};
+    use super::SplitKind;
+    use super::{add_feature_to_deps, build_env, cache_action_for, cache_cutoff};
+    use super::{cache_miss_explanation, cargo_command, check_edition_support_with, clean_cache};
+    use super::{clean_cache_package, clean_cache_packages, consts, find_default_bin};
+    use super::{generate_package, get_bin_path, get_default_bin, get_exe_path, get_pkg_manifest};
+    use super::{get_pkg_metadata, hash_file, insert_prelude, lock_package, manifest_bin_names};
+    use super::{manifest_pkg_name, merge_manifest, parse_rustc_version, parse_user_manifest};
+    use super::{remove_corrupt_package, rustflags_for, split_input, split_script};
+    use super::write_pkg_metadata;
+    use std::fs;
+    use std::io::prelude::*;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn test_parse_rustc_version() {
+        assert_eq!(parse_rustc_version("1.56.0"), Some((1, 56)));
+        assert_eq!(parse_rustc_version("1.50.0-nightly"), Some((1, 50)));
+        assert_eq!(parse_rustc_version(" 1.31.1 \n"), Some((1, 31)));
+        assert_eq!(parse_rustc_version("1"), None);
+        assert_eq!(parse_rustc_version("one.two"), None);
+        assert_eq!(parse_rustc_version(""), None);
+    }
+
+    #[test]
+    fn test_check_edition_support() {
+        let mani = "[package]\nname = \"foo\"\nedition = \"2021\"\n";
+
+        let err = check_edition_support_with(mani, || Ok((1, 50))).unwrap_err();
+        assert!(err.is_human());
+        assert_eq!(err.to_string(), "edition 2021 requires Rust 1.56+, found 1.50");
+
+        assert!(check_edition_support_with(mani, || Ok((1, 56))).is_ok());
+        assert!(check_edition_support_with(mani, || Ok((2, 0))).is_ok());
+    }
+
+    #[test]
+    fn test_check_edition_support_skips_rustc() {
+        // Without an edition we know about, there's no reason to go asking rustc.
+        fn no_rustc() -> Result<(u64, u64)> { panic!("rustc should not have been asked") }
+        assert!(check_edition_support_with("[package]\nname = \"foo\"\n", no_rustc).is_ok());
+        assert!(check_edition_support_with("[package]\nedition = \"3000\"\n", no_rustc).is_ok());
+    }
+
+    fn split(content: &str) -> Option<(String, &str, SplitKind)> {
+        split_script(content).map(|split| (split.manifest.into_owned(), split.source, split.kind))
+    }
+
+    #[test]
+    fn test_split_script_divider() {
+        assert_eq!(split("[dependencies]\ntime = \"0.1\"\n---\nfn main() {}\n"),
+            Some(("[dependencies]\ntime = \"0.1\"\n".into(), "\nfn main() {}\n", SplitKind::Divider)));
+        assert_eq!(split("time = \"0.1\"\n  -----  \nfn main() {}"),
+            Some(("time = \"0.1\"\n".into(), "\nfn main() {}", SplitKind::Divider)));
+    }
+
+    #[test]
+    fn test_split_script_marker() {
+        assert_eq!(split("[dependencies]\ntime = \"0.1\"\n\nuse std::io;\nfn main() {}\n"),
+            Some(("[dependencies]\ntime = \"0.1\"\n\n".into(), "use std::io;\nfn main() {}\n", SplitKind::Marker)));
+        assert_eq!(split("  fn main() {}"),
+            Some(("".into(), "  fn main() {}", SplitKind::Marker)));
+    }
+
+    #[test]
+    fn test_split_script_no_manifest() {
+        assert_eq!(split("fn main() {}\n"),
+            Some(("".into(), "fn main() {}\n", SplitKind::Marker)));
+        assert_eq!(split("// Just a comment.\nfn main() {}\n"),
+            Some(("".into(), "// Just a comment.\nfn main() {}\n", SplitKind::Marker)));
+    }
+
+    #[test]
+    fn test_split_script_manifest_only() {
+        assert_eq!(split("[dependencies]\ntime = \"0.1\"\n"), None);
+        assert_eq!(split(""), None);
+    }
+
+    #[test]
+    fn test_split_script_hashbang() {
+        // The hashbang stays in the manifest, where it's just a comment.
+        assert_eq!(split("#!/usr/bin/env run-cargo-script\ntime = \"0.1\"\n---\nfn main() {}"),
+            Some(("#!/usr/bin/env run-cargo-script\ntime = \"0.1\"\n".into(), "\nfn main() {}", SplitKind::Divider)));
+
+        // An inner attribute isn't a hashbang.
+        assert_eq!(split("#![allow(dead_code)]\nfn main() {}"),
+            Some(("".into(), "#![allow(dead_code)]\nfn main() {}", SplitKind::Marker)));
+    }
+
+    #[test]
+    fn test_split_script_directive() {
+        assert_eq!(split("// cargo-script: --debug\ntime = \"0.1\"\n---\nfn main() {}"),
+            Some(("\ntime = \"0.1\"\n".into(), "\nfn main() {}", SplitKind::Divider)));
+    }
+
+    #[test]
+    fn test_split_script_fence() {
+        assert_eq!(split("//! ```cargo\n//! [dependencies]\n//! time = \"0.1\"\n//! ```\nfn main() {}\n"),
+            Some(("[dependencies]\ntime = \"0.1\"\n".into(), "\nfn main() {}\n", SplitKind::Fence)));
+
+        // An unclosed fence isn't a manifest, so this is left to the markers.
+        assert_eq!(split("// ```cargo\n// time = \"0.1\"\nfn main() {}"),
+            Some(("".into(), "// ```cargo\n// time = \"0.1\"\nfn main() {}", SplitKind::Marker)));
+    }
+
+    #[test]
+    fn test_split_script_doc_comment() {
+        assert_eq!(split("//! [dependencies]\n//! time = \"0.1\"\nfn main() {}\n"),
+            Some(("[dependencies]\ntime = \"0.1\"\n".into(), "fn main() {}\n", SplitKind::DocComment)));
+
+        // Documentation that isn't TOML stays with the source.
+        assert_eq!(split("//! Prints the time.\nfn main() {}\n"),
+            Some(("".into(), "//! Prints the time.\nfn main() {}\n", SplitKind::Marker)));
+    }
+
+    #[test]
+    fn test_split_script_bom() {
+        assert_eq!(split("\u{feff}#!/usr/bin/env run-cargo-script\ntime = \"0.1\"\n---\nfn main() {}"),
+            Some(("#!/usr/bin/env run-cargo-script\ntime = \"0.1\"\n".into(), "\nfn main() {}", SplitKind::Divider)));
+        assert_eq!(split("\u{feff}fn main() {}"),
+            Some(("".into(), "fn main() {}", SplitKind::Marker)));
+        assert_eq!(split("\u{feff}time = \"0.1\"\nfn main() {}"),
+            Some(("time = \"0.1\"\n".into(), "fn main() {}", SplitKind::Marker)));
+    }
+
+    #[test]
+    fn test_sanitizer_build_flags() {
+        let meta = PackageMetadata {
+            sanitizer: Some("address".into()),
+            target: Some("x86_64-unknown-linux-gnu".into()),
+            ..PackageMetadata::default()
+        };
+        let pkg_path = Path::new("pkg");
+        assert_eq!(rustflags_for(&meta, pkg_path), vec!["-Z sanitizer=address".to_owned()]);
+
+        let cmd = format!("{:?}", cargo_command("build", pkg_path, &meta, &BuildSettings::default()));
+        assert!(cmd.contains("\"--target\" \"x86_64-unknown-linux-gnu\""), "{}", cmd);
+
+        assert_eq!(rustflags_for(&PackageMetadata::default(), pkg_path), Vec::<String>::new());
+    }
+
+    /**
+    Creates a fresh, empty folder for a test to play in.
+    */
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!("cargo-script-test-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /**
+    Creates a package in `dir` which looks like it was built from stdin just now.
+    */
+    fn fake_package(dir: &Path, id: &str) -> PathBuf {
+        let pkg_path = dir.join(id);
+        fs::create_dir_all(&pkg_path).unwrap();
+        write_pkg_metadata(&pkg_path, &PackageMetadata::default()).unwrap();
+        pkg_path
+    }
+
+    #[test]
+    fn test_cache_cutoff() {
+        assert_eq!(cache_cutoff(10000, 1000), 9000);
+        assert_eq!(cache_cutoff(1000, 1000), 0);
+        assert_eq!(cache_cutoff(1000, 10000), 0);
+        assert_eq!(cache_cutoff(1000, !0), 0);
+    }
+
+    #[test]
+    fn test_clean_cache_package_future_mtime() {
+        use std::fs::PathExt;
+
+        let dir = test_dir("clean-future");
+        let pkg_path = fake_package(&dir, "stdin-future");
+
+        // If the clock has gone backwards since the package was built, it was built "in the future".  That's no reason to remove it.
+        let cutoff = cache_cutoff(1000, 500);
+        clean_cache_package("stdin-future", &pkg_path, cutoff).unwrap();
+        assert!(pkg_path.is_dir());
+
+        // Whereas a package built before the cutoff really should go.
+        let md = fs::metadata(pkg_path.join(consts::METADATA_FILE)).unwrap();
+        clean_cache_package("stdin-future", &pkg_path, md.modified() + 1).unwrap();
+        assert!(!pkg_path.exists());
+    }
+
+    #[test]
+    fn test_safe_name() {
+        let name = |name| Input::File(name, Path::new("x.rs"), "", 0).safe_name();
+        assert_eq!(name("hello"), "hello");
+        assert_eq!(name("hello-world_2"), "hello-world_2");
+        assert_eq!(name("hello.world"), "hello_world");
+        assert_eq!(name("hello world"), "hello_world");
+        assert_eq!(name("2fast"), "_fast");
+        assert_eq!(name("caf\u{e9}"), "caf_");
+        assert_eq!(name(""), "script");
+
+        assert_eq!(Input::Stdin("").safe_name(), "stdin");
+        assert_eq!(Input::Expr("", ExprStyle::Debug).safe_name(), "expr");
+        assert_eq!(Input::Loop("", false, b'\n').safe_name(), "loop");
+    }
+
+    #[test]
+    fn test_insert_prelude() {
+        assert_eq!(insert_prelude("fn main() {}\n", "use std::io;"),
+            "use std::io;\nfn main() {}\n");
+        assert_eq!(insert_prelude("#![allow(unused)]\n// Hi.\n#![deny(warnings)]\nfn main() {}", "use std::io;"),
+            "#![allow(unused)]\n// Hi.\n#![deny(warnings)]\nuse std::io;\nfn main() {}");
+        assert_eq!(insert_prelude("#![allow(unused)]", "use std::io;"),
+            "#![allow(unused)]\nuse std::io;\n");
+    }
+
+    #[test]
+    fn test_prelude_helper() {
+        let meta = PackageMetadata {
+            prelude: Some("fn double(x: i32) -> i32 { x * 2 }".into()),
+            ..PackageMetadata::default()
+        };
+        let (_, source) = split_input(&Input::Expr("double(21)", ExprStyle::Debug), &meta,
+            &BuildSettings::default()).unwrap();
+        let helper = source.find("fn double").expect("prelude is missing");
+        let main = source.find("fn main").expect("main is missing");
+        assert!(helper < main);
+        assert!(source.contains("double(21)"));
+    }
+
+    /**
+    Points the cache somewhere tests can mess with.  Every test uses the same place, so it doesn't matter which one sets it first.
+    */
+    fn test_cache() -> PathBuf {
+        let cache_path = ::std::env::temp_dir().join("cargo-script-test-cache");
+        ::std::env::set_var(consts::CACHE_DIR_ENV, &cache_path);
+        cache_path
+    }
+
+    /**
+    Pretends to build the input into the test cache: its package is generated as usual, but each executable is just a file with some junk in it.  Returns the package's path.
+    */
+    fn fake_build(input: &Input, meta: &PackageMetadata) -> PathBuf {
+        let pkg_path = test_cache().join(input.compute_id(meta).unwrap());
+        let _ = fs::remove_dir_all(&pkg_path);
+        generate_package(input, meta, &pkg_path, &BuildSettings::default()).unwrap();
+
+        let mani = get_pkg_manifest(&pkg_path).unwrap();
+        let bins = match find_default_bin(input, &mani, meta).unwrap() {
+            Some(bin) => vec![bin],
+            None => manifest_bin_names(&mani)
+        };
+        for bin in &bins {
+            let exe_path = get_bin_path(&pkg_path, meta, bin);
+            fs::create_dir_all(exe_path.parent().unwrap()).unwrap();
+            fs::File::create(&exe_path).unwrap().write_all(b"not really an executable").unwrap();
+        }
+
+        let mut meta = meta.clone();
+        if bins.len() == 1 {
+            let exe_path = get_bin_path(&pkg_path, &meta, &bins[0]);
+            meta.exe_size = Some(fs::metadata(&exe_path).unwrap().len());
+            meta.exe_hash = Some(hash_file(&exe_path).unwrap());
+        }
+        write_pkg_metadata(&pkg_path, &meta).unwrap();
+        pkg_path
+    }
+
+    #[test]
+    fn test_truncated_exe_is_rebuilt() {
+        let input = Input::Stdin("fn main() { /* truncated */ }");
+        let meta = PackageMetadata::default();
+        let pkg_path = fake_build(&input, &meta);
+
+        let (action, _, _) = cache_action_for(&input, meta.clone(), false, false).unwrap();
+        assert_eq!(action, CacheAction::Execute);
+
+        fs::File::create(get_bin_path(&pkg_path, &meta, "stdin")).unwrap();
+        let (action, _, _) = cache_action_for(&input, meta, false, false).unwrap();
+        assert_eq!(action, CacheAction::Compile);
+    }
+
+    #[test]
+    fn test_run_all_bins_are_cached() {
+        let input = Input::Stdin("[[bin]]\nname = \"one\"\npath = \"stdin.rs\"\n\
+            [[bin]]\nname = \"two\"\npath = \"stdin.rs\"\n---\nfn main() {}");
+        let meta = PackageMetadata::default();
+        let pkg_path = fake_build(&input, &meta);
+
+        // There isn't a single executable to run...
+        assert_eq!(find_default_bin(&input, &get_pkg_manifest(&pkg_path).unwrap(), &meta).unwrap(), None);
+        assert!(get_default_bin(&input, &pkg_path, &meta).unwrap_err().is_human());
+
+        // ...but the package is still cached, so long as all of them are there.
+        let (action, _, _) = cache_action_for(&input, meta.clone(), false, true).unwrap();
+        assert_eq!(action, CacheAction::Execute);
+
+        fs::remove_file(get_bin_path(&pkg_path, &meta, "two")).unwrap();
+        let (action, _, _) = cache_action_for(&input, meta, false, false).unwrap();
+        assert_eq!(action, CacheAction::Compile);
+    }
+
+    #[test]
+    fn test_stored_exe_path_is_used() {
+        let input = Input::Stdin("fn main() { /* stored exe path */ }");
+        let meta = PackageMetadata::default();
+        let pkg_path = fake_build(&input, &meta);
+
+        // Move the executable somewhere Cargo might have put it instead, and say so in the metadata.
+        let default_path = get_bin_path(&pkg_path, &meta, "stdin");
+        let exe_path = pkg_path.join("elsewhere");
+        fs::rename(&default_path, &exe_path).unwrap();
+        let mut cache_meta = get_pkg_metadata(&pkg_path).unwrap();
+        cache_meta.exe_path = Some(exe_path.to_string_lossy().into_owned());
+        write_pkg_metadata(&pkg_path, &cache_meta).unwrap();
+
+        let (action, _, meta) = cache_action_for(&input, meta, false, true).unwrap();
+        assert_eq!(action, CacheAction::Execute);
+        assert_eq!(get_exe_path(&input, &pkg_path, &meta).unwrap(), exe_path);
+    }
+
+    #[test]
+    fn test_split_script_fence_async_main() {
+        // None of the markers would ever spot `async`, so without the fence, there'd be nowhere to split.
+        let script = "// ```cargo\n// [dependencies]\n// tokio = \"1\"\n// ```\nasync fn main() {}\n";
+        assert_eq!(split(script),
+            Some(("[dependencies]\ntokio = \"1\"\n".into(), "\nasync fn main() {}\n", SplitKind::Fence)));
+        assert_eq!(split("[dependencies]\ntokio = \"1\"\nasync fn main() {}\n"), None);
+    }
+
+    #[test]
+    fn test_toolchain_changes_id() {
+        let input = Input::Expr("1 + 1", ExprStyle::Debug);
+        let meta = |toolchain: Option<&str>| PackageMetadata {
+            toolchain: toolchain.map(String::from),
+            ..PackageMetadata::default()
+        };
+        let stable = input.compute_id(&meta(Some("stable"))).unwrap();
+        let nightly = input.compute_id(&meta(Some("nightly"))).unwrap();
+        let default = input.compute_id(&meta(None)).unwrap();
+        assert!(stable != nightly);
+        assert!(stable != default && nightly != default);
+    }
+
+    #[test]
+    fn test_features_change_id() {
+        let input = Input::Expr("1 + 1", ExprStyle::Debug);
+        let meta = |features: &[&str]| PackageMetadata {
+            features: features.iter().map(|s| s.to_string()).collect(),
+            ..PackageMetadata::default()
+        };
+        let none = input.compute_id(&meta(&[])).unwrap();
+        let foo = input.compute_id(&meta(&["foo"])).unwrap();
+        let bar = input.compute_id(&meta(&["bar"])).unwrap();
+        let both = input.compute_id(&meta(&["foo", "bar"])).unwrap();
+        assert!(none != foo && none != bar && none != both);
+        assert!(foo != bar && foo != both && bar != both);
+    }
+
+    #[test]
+    fn test_expr_style_changes_id() {
+        let meta = PackageMetadata::default();
+        let id = |style| Input::Expr("1 + 1", style).compute_id(&meta).unwrap();
+        let debug = id(ExprStyle::Debug);
+        let pretty = id(ExprStyle::Pretty);
+        let json = id(ExprStyle::Json);
+        assert!(debug != pretty && debug != json && pretty != json);
+    }
+
+    #[test]
+    fn test_loop_separator_changes_id() {
+        let meta = PackageMetadata::default();
+        let id = |sep| Input::Loop("line", false, sep).compute_id(&meta).unwrap();
+        assert!(id(b'\n') != id(b','));
+        assert!(id(b',') != id(0));
+    }
+
+    #[test]
+    fn test_merge_manifest_nested() {
+        let into_t = parse_user_manifest("test", r#"
+            [package]
+            name = "n"
+            [profile.release]
+            opt-level = 2
+            lto = false
+        "#).unwrap();
+        let from_t = parse_user_manifest("test", r#"
+            [profile.release]
+            opt-level = 3
+            debug = true
+        "#).unwrap();
+        let mani = ::toml::Value::Table(merge_manifest(into_t, from_t).unwrap());
+
+        assert_eq!(mani.lookup("package.name").and_then(|v| v.as_str()), Some("n"));
+        assert_eq!(mani.lookup("profile.release.opt-level").and_then(|v| v.as_integer()), Some(3));
+        assert_eq!(mani.lookup("profile.release.debug").and_then(|v| v.as_bool()), Some(true));
+        assert_eq!(mani.lookup("profile.release.lto").and_then(|v| v.as_bool()), Some(false));
+    }
+
+    #[test]
+    fn test_merge_manifest_table_over_value() {
+        let into_t = parse_user_manifest("test", r#"
+            [dependencies]
+            foo = "0.1"
+            bar = "1"
+        "#).unwrap();
+        let from_t = parse_user_manifest("test", r#"
+            [dependencies.foo]
+            git = "https://example.com/foo.git"
+        "#).unwrap();
+        let mani = ::toml::Value::Table(merge_manifest(into_t, from_t).unwrap());
+
+        assert_eq!(mani.lookup("dependencies.foo.git").and_then(|v| v.as_str()),
+            Some("https://example.com/foo.git"));
+        assert_eq!(mani.lookup("dependencies.foo.version"), None);
+        assert_eq!(mani.lookup("dependencies.bar").and_then(|v| v.as_str()), Some("1"));
+    }
+
+    #[test]
+    fn test_split_input_unsupported_sections() {
+        let meta = PackageMetadata::default();
+        let settings = BuildSettings { quiet: true, ..BuildSettings::default() };
+        let split = |content| split_input(&Input::Stdin(content), &meta, &settings);
+
+        let err = split("[workspace]\nmembers = []\n---\nfn main() {}\n").unwrap_err();
+        assert!(err.is_human());
+
+        let (mani_str, _) = split("[lib]\npath = \"lib.rs\"\n---\nfn main() {}\n").unwrap();
+        assert!(mani_str.contains("[lib]"));
+
+        let (mani_str, _) = split("[[bin]]\nname = \"other\"\npath = \"other.rs\"\n---\nfn main() {}\n").unwrap();
+        assert!(mani_str.contains("[[bin]]"));
+        assert!(mani_str.contains("other.rs"));
+    }
+
+    #[test]
+    fn test_split_input_doc_comment_matches_divider() {
+        let meta = PackageMetadata::default();
+        let settings = BuildSettings::default();
+        let split = |content| split_input(&Input::Stdin(content), &meta, &settings).unwrap();
+
+        let (doc_mani, doc_source) = split("//! [dependencies]\n//! time = \"0.1\"\nfn main() {}\n");
+        let (div_mani, div_source) = split("[dependencies]\ntime = \"0.1\"\n---\nfn main() {}\n");
+        assert_eq!(doc_mani, div_mani);
+        assert_eq!(doc_source.trim(), div_source.trim());
+    }
+
+    #[test]
+    fn test_template_changes_id() {
+        let input = Input::Expr("1 + 1", ExprStyle::Debug);
+        let meta = |template: Option<&str>| PackageMetadata {
+            template: template.map(String::from),
+            ..PackageMetadata::default()
+        };
+        let builtin = input.compute_id(&meta(None)).unwrap();
+        let first = input.compute_id(&meta(Some("fn main() { println!(\"{}\", %%); }"))).unwrap();
+        let second = input.compute_id(&meta(Some("fn main() { println!(\"{:?}\", %%); }"))).unwrap();
+        assert!(builtin != first && builtin != second);
+        assert!(first != second);
+    }
+
+    #[test]
+    fn test_clean_cache_max_age_before_time() {
+        use std::fs::PathExt;
+
+        let input = Input::Stdin("fn main() { /* ancient max age */ }");
+        let meta = PackageMetadata::default();
+        let pkg_path = fake_build(&input, &meta);
+
+        // A maximum age that goes back further than the clock makes everything young enough to keep.
+        clean_cache(!0).unwrap();
+        assert!(pkg_path.is_dir());
+        let (action, _, _) = cache_action_for(&input, meta, false, false).unwrap();
+        assert_eq!(action, CacheAction::Execute);
+    }
+
+    #[test]
+    fn test_corrupt_package_is_only_removed_when_building() {
+        use std::fs::PathExt;
+
+        let input = Input::Stdin("fn main() { /* corrupt metadata */ }");
+        let meta = PackageMetadata::default();
+        let pkg_path = fake_build(&input, &meta);
+        fs::File::create(pkg_path.join(consts::METADATA_FILE)).unwrap().write_all(b"{ nope").unwrap();
+
+        // Just asking what to do (as `--dry-run` does) mustn't touch anything.
+        let (action, _, _) = cache_action_for(&input, meta, false, false).unwrap();
+        assert_eq!(action, CacheAction::Compile);
+        assert!(pkg_path.join(consts::METADATA_FILE).is_file());
+
+        // Whoever holds the lock is the one doing the removing, so the lock stays.
+        let lock_path = lock_package(&pkg_path, true, 0).unwrap();
+        remove_corrupt_package(&pkg_path);
+        assert!(!pkg_path.join(consts::METADATA_FILE).exists());
+        assert!(!pkg_path.join("Cargo.toml").exists());
+        let left: Vec<_> = fs::read_dir(&pkg_path).unwrap().map(|e| e.unwrap().path()).collect();
+        assert_eq!(left, vec![lock_path]);
+    }
+
+    #[test]
+    fn test_split_input_crlf() {
+        let meta = PackageMetadata::default();
+        let settings = BuildSettings::default();
+        let split_both = |lf: &str| {
+            let crlf = lf.replace("\n", "\r\n");
+            let (lf_mani, lf_source) = split_input(&Input::Stdin(lf), &meta, &settings).unwrap();
+            let (crlf_mani, crlf_source) = split_input(&Input::Stdin(&crlf), &meta, &settings).unwrap();
+            assert_eq!(crlf_mani, lf_mani);
+            assert_eq!(crlf_source.replace("\r\n", "\n"), lf_source);
+            assert_eq!(split(&crlf).map(|s| s.2), split(lf).map(|s| s.2));
+        };
+
+        split_both("[dependencies]\ntime = \"0.1\"\n---\nfn main() {}\n");
+        split_both("[dependencies]\ntime = \"0.1\"\n\nuse std::io;\nfn main() {}\n");
+        split_both("//! [dependencies]\n//! time = \"0.1\"\nfn main() {}\n");
+        split_both("// ```cargo\n// [dependencies]\n// time = \"0.1\"\n// ```\nfn main() {}\n");
+    }
+
+    #[test]
+    fn test_add_feature_to_deps() {
+        let mut mani = parse_user_manifest("test", r#"
+            [dependencies]
+            plain = "1"
+            tabled = { version = "2", features = ["a"] }
+            already = { version = "3", features = ["serde"] }
+            without = "4"
+        "#).unwrap();
+        add_feature_to_deps(&mut mani, &["plain", "tabled", "already"], "serde");
+        let mani = ::toml::Value::Table(mani);
+
+        let features = |dep: &str| mani.lookup(&format!("dependencies.{}.features", dep))
+            .and_then(|v| v.as_slice())
+            .map(|fs| fs.iter().filter_map(|f| f.as_str()).map(String::from).collect::<Vec<_>>());
+        assert_eq!(features("plain"), Some(vec!["serde".into()]));
+        assert_eq!(mani.lookup("dependencies.plain.version").and_then(|v| v.as_str()), Some("1"));
+        assert_eq!(features("tabled"), Some(vec!["a".into(), "serde".into()]));
+        assert_eq!(features("already"), Some(vec!["serde".into()]));
+        assert_eq!(mani.lookup("dependencies.without").and_then(|v| v.as_str()), Some("4"));
+    }
+
+    #[test]
+    fn test_split_input_deps_inline_features() {
+        let meta = PackageMetadata {
+            deps_inline: Some("[dependencies]\nfoo = { version = \"1\", features = [\"x\"] }".into()),
+            ..PackageMetadata::default()
+        };
+        let settings = BuildSettings::default();
+        let input = Input::Stdin("[dependencies]\nfoo = \"0.1\"\nbar = \"2\"\n---\nfn main() {}\n");
+        let (mani_str, _) = split_input(&input, &meta, &settings).unwrap();
+        let mani = ::toml::Value::Table(parse_user_manifest("test", &mani_str).unwrap());
+
+        assert_eq!(mani.lookup("dependencies.foo.version").and_then(|v| v.as_str()), Some("1"));
+        assert_eq!(mani.lookup("dependencies.foo.features.0").and_then(|v| v.as_str()), Some("x"));
+        assert_eq!(mani.lookup("dependencies.bar").and_then(|v| v.as_str()), Some("2"));
+    }
+
+    #[test]
+    fn test_split_input_deps_inline_one_line() {
+        let meta = PackageMetadata {
+            deps_inline: Some("dependencies = { time = \"0.1.25\" }".into()),
+            ..PackageMetadata::default()
+        };
+        let (mani_str, _) = split_input(&Input::Stdin("fn main() {}"), &meta, &BuildSettings::default()).unwrap();
+        let mani = ::toml::Value::Table(parse_user_manifest("test", &mani_str).unwrap());
+        assert_eq!(mani.lookup("dependencies.time").and_then(|v| v.as_str()), Some("0.1.25"));
+    }
+
+    #[test]
+    fn test_target_feature_rustflags() {
+        let meta = PackageMetadata {
+            target_features: Some("+avx2,+fma".into()),
+            rustc_flags: vec!["-g".into()],
+            ..PackageMetadata::default()
+        };
+        assert_eq!(rustflags_for(&meta, Path::new("pkg")),
+            vec!["-C target-feature=+avx2,+fma".to_owned(), "-g".into()]);
+    }
+
+    #[test]
+    fn test_find_default_bin_default_run() {
+        let input = Input::Stdin("fn main() {}");
+        let meta = PackageMetadata::default();
+        let mani = |default_run: &str| parse_user_manifest("test", &format!(r#"
+            [package]
+            name = "n"
+            {}
+            [[bin]]
+            name = "a"
+            [[bin]]
+            name = "b"
+        "#, default_run)).unwrap();
+
+        assert_eq!(find_default_bin(&input, &mani(""), &meta).unwrap(), None);
+        assert_eq!(find_default_bin(&input, &mani("default-run = \"b\""), &meta).unwrap(), Some("b".into()));
+        assert!(find_default_bin(&input, &mani("default-run = \"c\""), &meta).unwrap_err().is_human());
+
+        // `--bin` still wins.
+        let meta = PackageMetadata { bin: Some("a".into()), ..PackageMetadata::default() };
+        assert_eq!(find_default_bin(&input, &mani("default-run = \"b\""), &meta).unwrap(), Some("a".into()));
+    }
+
+    #[test]
+    fn test_cache_miss_explanation() {
+        let cache_meta = PackageMetadata { path: Some("x.rs".into()), ..PackageMetadata::default() };
+        let input_meta = PackageMetadata { debug: true, ..cache_meta.clone() };
+
+        let explanation = cache_miss_explanation("options changed", Some(&cache_meta), &input_meta);
+        assert_eq!(explanation.find("reason").and_then(|r| r.as_string()), Some("options changed"));
+        let changed = explanation.find("changed").and_then(|c| c.as_object()).unwrap();
+        assert_eq!(changed.keys().collect::<Vec<_>>(), vec!["debug"]);
+        assert_eq!(explanation.find_path(&["changed", "debug", "cached"]).and_then(|v| v.as_boolean()), Some(false));
+        assert_eq!(explanation.find_path(&["changed", "debug", "current"]).and_then(|v| v.as_boolean()), Some(true));
+
+        // Without anything cached, there's nothing to compare.
+        let explanation = cache_miss_explanation("not cached", None, &input_meta);
+        assert!(explanation.find("changed").is_none());
+    }
+
+    #[test]
+    fn test_cargo_command_jobs() {
+        let meta = PackageMetadata::default();
+        let cmd = format!("{:?}", cargo_command("build", Path::new("pkg"), &meta, &BuildSettings::default()));
+        assert!(!cmd.contains("--jobs"), "{}", cmd);
+
+        let settings = BuildSettings { jobs: Some(3), ..BuildSettings::default() };
+        let cmd = format!("{:?}", cargo_command("build", Path::new("pkg"), &meta, &settings));
+        assert!(cmd.contains("\"--jobs\" \"3\""), "{}", cmd);
+    }
+
+    #[test]
+    fn test_cargo_command_color() {
+        let input = Input::Stdin("fn main() { let unused = 1; }");
+        let meta = PackageMetadata::default();
+        let cargo_stderr = |name: &str, color| {
+            let pkg_path = test_dir(name);
+            generate_package(&input, &meta, &pkg_path, &BuildSettings::default()).unwrap();
+            let settings = BuildSettings { color: color, ..BuildSettings::default() };
+            let out = cargo_command("check", &pkg_path, &meta, &settings).output().unwrap();
+            assert!(out.status.success());
+            String::from_utf8_lossy(&out.stderr).into_owned()
+        };
+
+        let never = cargo_stderr("color-never", Color::Never);
+        assert!(never.contains("unused"), "{}", never);
+        assert!(!never.contains('\x1b'), "{}", never);
+
+        let always = cargo_stderr("color-always", Color::Always);
+        assert!(always.contains('\x1b'), "{}", always);
+    }
+
+    #[test]
+    fn test_split_input_dep_overrides() {
+        let meta = PackageMetadata {
+            deps: vec![("time".into(), "0.1".into())],
+            dep_overrides: vec![("libc".into(), "{ git = \"https://example.com/libc.git\" }".into())],
+            ..PackageMetadata::default()
+        };
+        let (mani_str, _) = split_input(&Input::Stdin("fn main() {}"), &meta, &BuildSettings::default()).unwrap();
+        let mani = ::toml::Value::Table(parse_user_manifest("test", &mani_str).unwrap());
+
+        assert_eq!(mani.lookup("patch.crates-io.libc.git").and_then(|v| v.as_str()),
+            Some("https://example.com/libc.git"));
+        assert_eq!(mani.lookup("dependencies.time").and_then(|v| v.as_str()), Some("0.1"));
+        assert!(mani.lookup("dependencies.libc").is_none());
+    }
+
+    #[test]
+    fn test_cargo_command_quiet() {
+        let meta = PackageMetadata::default();
+        let cmd = format!("{:?}", cargo_command("build", Path::new("pkg"), &meta, &BuildSettings::default()));
+        assert!(!cmd.contains("\"--quiet\""), "{}", cmd);
+
+        let settings = BuildSettings { quiet_cargo: true, ..BuildSettings::default() };
+        let cmd = format!("{:?}", cargo_command("build", Path::new("pkg"), &meta, &settings));
+        assert!(cmd.contains("\"--quiet\""), "{}", cmd);
+    }
+
+    #[test]
+    fn test_reproducible_build_env() {
+        let meta = PackageMetadata { reproducible: true, ..PackageMetadata::default() };
+        let pkg_path = Path::new("pkg");
+
+        let flags = rustflags_for(&meta, pkg_path);
+        assert_eq!(flags[0], "--remap-path-prefix=pkg=.");
+        assert!(flags[1..].iter().all(|f| f.starts_with("--remap-path-prefix=") && f.ends_with("=/cargo")),
+            "{:?}", flags);
+
+        let env = build_env(&meta, pkg_path);
+        let var = |name: &str| env.iter().find(|&&(k, _)| k == name).map(|&(_, ref v)| v.clone());
+        assert!(var("RUSTFLAGS").unwrap().contains("--remap-path-prefix=pkg=."));
+        assert_eq!(var("CARGO_INCREMENTAL"), Some("0".into()));
+        assert_eq!(var("SOURCE_DATE_EPOCH"), Some(::std::env::var("SOURCE_DATE_EPOCH").unwrap_or("0".into())));
+
+        assert!(build_env(&PackageMetadata::default(), pkg_path).is_empty());
+    }
+
+    #[test]
+    fn test_cargo_command_offline() {
+        let meta = PackageMetadata::default();
+        let cmd = format!("{:?}", cargo_command("build", Path::new("pkg"), &meta, &BuildSettings::default()));
+        assert!(!cmd.contains("--offline"), "{}", cmd);
+
+        let settings = BuildSettings { offline: true, ..BuildSettings::default() };
+        let cmd = format!("{:?}", cargo_command("build", Path::new("pkg"), &meta, &settings));
+        assert!(cmd.contains("\"--offline\""), "{}", cmd);
+    }
+
+    #[test]
+    fn test_file_id_tracks_content() {
+        let path = Path::new("/scripts/edited.rs");
+        let meta = PackageMetadata::default();
+        let id = |content: &str, meta: &PackageMetadata|
+            Input::File("edited", path, content, 0).compute_id(meta).unwrap().to_string_lossy().into_owned();
+
+        let before = id("fn main() {}", &meta);
+        let after = id("fn main() { println!(\"edited\"); }", &meta);
+        assert!(before.starts_with("file-edited-") && after.starts_with("file-edited-"), "{} {}", before, after);
+        assert!(before != after);
+        assert_eq!(id("fn main() {}", &meta), before);
+
+        let with_deps = PackageMetadata { deps: vec![("time".into(), "0.1".into())], ..PackageMetadata::default() };
+        assert!(id("fn main() {}", &with_deps) != before);
+    }
+
+    #[test]
+    fn test_split_input_edition() {
+        let settings = BuildSettings::default();
+        let meta = PackageMetadata { edition: Some("2018".into()), ..PackageMetadata::default() };
+        let edition = |input: &Input| {
+            let (mani_str, _) = split_input(input, &meta, &settings).unwrap();
+            let mani = ::toml::Value::Table(parse_user_manifest("test", &mani_str).unwrap());
+            mani.lookup("package.edition").and_then(|v| v.as_str()).map(String::from)
+        };
+
+        assert_eq!(edition(&Input::Stdin("fn main() {}")), Some("2018".into()));
+
+        // The script's own manifest has the last word.
+        assert_eq!(edition(&Input::Stdin("[package]\nedition = \"2021\"\n---\nfn main() {}")), Some("2021".into()));
+    }
+
+    #[test]
+    fn test_clean_cache_package_orphaned() {
+        use std::fs::PathExt;
+
+        let dir = test_dir("clean-orphaned");
+        let script_path = dir.join("script.rs");
+        fs::File::create(&script_path).unwrap();
+
+        let package_for = |id: &str, script_path: &Path| {
+            let pkg_path = fake_package(&dir, id);
+            let meta = PackageMetadata {
+                path: Some(script_path.to_string_lossy().into_owned()),
+                ..PackageMetadata::default()
+            };
+            write_pkg_metadata(&pkg_path, &meta).unwrap();
+            pkg_path
+        };
+        let kept = package_for("file-script", &script_path);
+        let orphaned = package_for("file-gone", &dir.join("gone.rs"));
+
+        clean_cache_package("file-script", &kept, 0).unwrap();
+        clean_cache_package("file-gone", &orphaned, 0).unwrap();
+        assert!(kept.is_dir());
+        assert!(!orphaned.exists());
+    }
+
+    #[test]
+    fn test_lock_package() {
+        let pkg_path = test_dir("lock-package");
+
+        let lock_path = lock_package(&pkg_path, true, 0).unwrap();
+        assert_eq!(lock_path, pkg_path.join(consts::LOCK_DIR));
+
+        // Someone else holds it, so this gives up, saying where the lock is.
+        let err = lock_package(&pkg_path, true, 2 * consts::LOCK_POLL_MS).unwrap_err();
+        assert!(err.is_human());
+        assert!(err.to_string().contains(&format!("{:?}", lock_path)), "{}", err);
+
+        // Whereas this gets it as soon as the holder lets go.
+        let waiter = {
+            let pkg_path = pkg_path.clone();
+            ::std::thread::spawn(move || lock_package(&pkg_path, true, consts::LOCK_TIMEOUT_MS).unwrap())
+        };
+        ::std::thread::sleep_ms(2 * consts::LOCK_POLL_MS);
+        fs::remove_dir(&lock_path).unwrap();
+        assert_eq!(waiter.join().unwrap(), lock_path);
+        assert!(fs::metadata(&lock_path).unwrap().is_dir());
+    }
+
+    #[test]
+    fn test_split_input_package_name() {
+        let settings = BuildSettings::default();
+        let meta = PackageMetadata::default();
+        let bin_for = |input: &Input| {
+            let (mani_str, _) = split_input(input, &meta, &settings).unwrap();
+            let mani = parse_user_manifest("test", &mani_str).unwrap();
+            assert_eq!(manifest_pkg_name(&mani), Some("custom"));
+            find_default_bin(input, &mani, &meta).unwrap()
+        };
+
+        // The generated binary has a name of its own, so renaming the package doesn't move it.
+        assert_eq!(bin_for(&Input::Stdin("[package]\nname = \"custom\"\n---\nfn main() {}")),
+            Some("stdin".into()));
+
+        // Whereas one the script declares without a name gets the package's, just as Cargo does.
+        let script = "[package]\nname = \"custom\"\n[[bin]]\npath = \"stdin.rs\"\n---\nfn main() {}";
+        assert_eq!(bin_for(&Input::Stdin(script)), Some("custom".into()));
+    }
+
+    #[test]
+    fn test_get_bin_path_target() {
+        let exe = |path: PathBuf| {
+            let mut path = path.into_os_string();
+            path.push(::std::env::consts::EXE_SUFFIX);
+            PathBuf::from(path)
+        };
+        let pkg_path = Path::new("pkg");
+
+        let meta = PackageMetadata::default();
+        assert_eq!(get_bin_path(pkg_path, &meta, "foo"), exe(pkg_path.join("target").join("release").join("foo")));
+
+        let meta = PackageMetadata { target: Some("wasm32-unknown-unknown".into()), debug: true, ..meta };
+        assert_eq!(get_bin_path(pkg_path, &meta, "foo"),
+            exe(pkg_path.join("target").join("wasm32-unknown-unknown").join("debug").join("foo")));
+    }
+
+    #[test]
+    fn test_changed_lock_is_rebuilt() {
+        let input = Input::Stdin("[dependencies]\ntime = \"*\"\n---\nfn main() { /* changed lock */ }");
+        let meta = PackageMetadata::default();
+        let pkg_path = fake_build(&input, &meta);
+
+        // Pretend Cargo resolved the wildcard, just as `compile` would record it.
+        let lock_path = pkg_path.join(consts::CARGO_LOCK_FILE);
+        fs::File::create(&lock_path).unwrap()
+            .write_all(b"[[package]]\nname = \"time\"\nversion = \"0.1.25\"\n").unwrap();
+        let mut cache_meta = get_pkg_metadata(&pkg_path).unwrap();
+        cache_meta.lock_hash = Some(hash_file(&lock_path).unwrap());
+        write_pkg_metadata(&pkg_path, &cache_meta).unwrap();
+
+        let (action, _, _) = cache_action_for(&input, meta.clone(), false, false).unwrap();
+        assert_eq!(action, CacheAction::Execute);
+
+        // Then someone runs `cargo update` in there.
+        fs::File::create(&lock_path).unwrap()
+            .write_all(b"[[package]]\nname = \"time\"\nversion = \"0.1.26\"\n").unwrap();
+        let (action, _, _) = cache_action_for(&input, meta, false, false).unwrap();
+        assert_eq!(action, CacheAction::Compile);
+    }
+
+    #[test]
+    fn test_clean_cache_skips_unreadable() {
+        use std::fs::PathExt;
+
+        let dir = test_dir("clean-unreadable");
+        let corrupt_path = fake_package(&dir, "a-corrupt");
+        fs::File::create(corrupt_path.join(consts::METADATA_FILE)).unwrap().write_all(b"{ nope").unwrap();
+        let partial_path = dir.join("b-partial");
+        fs::create_dir(&partial_path).unwrap();
+        let orphan_path = fake_package(&dir, "c-orphaned");
+        let meta = PackageMetadata {
+            path: Some(dir.join("gone.rs").to_string_lossy().into_owned()),
+            ..PackageMetadata::default()
+        };
+        write_pkg_metadata(&orphan_path, &meta).unwrap();
+
+        // Neither of the first two can be made sense of, so they're left alone, but that doesn't stop the last one being cleaned.
+        clean_cache_packages(&dir, 0).unwrap();
+        assert!(corrupt_path.is_dir());
+        assert!(partial_path.is_dir());
+        assert!(!orphan_path.exists());
+    }
+}
@@ -8,89 +8,343 @@ As such, `cargo-script` does two major things:
 1. Given a script, it extracts the embedded Cargo manifest and merges it with some sensible defaults.  This manifest, along with the source code, is written to a fresh Cargo package on-disk.
 
 2. It caches the generated and compiled packages, regenerating them only if the script or its metadata have changed.
+
+Both of these live in the `cargo_script` library; this is just the command-line interface on top of it.
 */
 #![allow(deprecated)] // for file metadata
-#![feature(collections)]
 #![feature(fs_time)]
 #![feature(path_ext)]
 
+extern crate cargo_script;
 extern crate docopt;
 extern crate env_logger;
-extern crate flate2;
 #[macro_use] extern crate log;
 extern crate rustc_serialize;
-extern crate shaman;
 extern crate toml;
 
-/**
-If this is set to `true`, the digests used for package IDs will be replaced with "stub" to make testing a bit easier.  Obviously, you don't want this `true` for release...
-*/
-const STUB_HASHES: bool = false;
-
-mod consts;
-mod error;
-mod platform;
-mod util;
-
-use std::error::Error;
-use std::ffi::OsString;
+use std::cell::Cell;
 use std::fs;
 use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::process::Command;
-
-use error::{Blame, MainError};
-
-type Result<T> = std::result::Result<T, MainError>;
-
-#[derive(Debug, RustcDecodable)]
+use std::process::{Command, Stdio};
+use std::time::Duration;
+use cargo_script::{consts, util};
+use cargo_script::{BuildSettings, CacheAction, Color, ExprStyle, Input, PackageMetadata, Result};
+use cargo_script::{cache_action_for, cache_cutoff, cargo_command, clean_cache, compile, current_time};
+use cargo_script::{ensure_compiled, generate_package};
+use cargo_script::{get_bin_path, get_cache_packages, get_cache_path, get_config_path, get_exe_path};
+use cargo_script::{get_lib_path, get_pkg_manifest, get_pkg_metadata, get_rustc_version_info};
+use cargo_script::{manifest_bin_names, manifest_env, parse_user_manifest};
+use cargo_script::{split_input, strip_script_directive, toolchain_command};
+use cargo_script::error::{Blame, MainError};
+
+#[derive(Clone, Debug, RustcDecodable)]
 struct Args {
     arg_script: Option<String>,
+    arg_args: Vec<String>,
+
+    flag_verbose: usize,
 
     flag_expr: Option<String>,
+    flag_expr_file: Option<String>,
+    flag_expr_out: Option<String>,
+    flag_pretty: bool,
+    flag_json: bool,
     flag_loop: Option<String>,
     flag_count: bool,
+    flag_split: Option<String>,
 
     flag_build_only: bool,
+    flag_print_exe_path: bool,
+    flag_color: Color,
+    flag_cargo_subcommand: Option<String>,
+    flag_check: bool,
+    flag_crate_type: Option<String>,
+    flag_bin: Option<String>,
+    flag_tree: bool,
+    flag_run_all: bool,
+    flag_run_tests_then_main: bool,
+    flag_warm: bool,
+    flag_gen_pkg_only: Option<String>,
+    flag_manifest_only: bool,
+    flag_clean: bool,
+    flag_dry_run: bool,
+    flag_keep_going: bool,
+    flag_artifact_manifest: Option<String>,
+    flag_run_artifact: Option<String>,
+    flag_stats: bool,
+    flag_list_cache: bool,
     flag_debug: bool,
+    flag_release: bool,
     flag_dep: Vec<String>,
+    flag_dep_feature: Vec<String>,
+    flag_dep_override: Vec<String>,
+    flag_dep_from: Option<String>,
+    flag_deps_inline: Option<String>,
+    flag_explain_cache_miss: bool,
     flag_force: bool,
+    flag_no_cache: bool,
+    flag_max_cache_age: Option<String>,
+    flag_max_jobs: Option<u32>,
+    flag_edition: Option<String>,
+    flag_no_edition_check: bool,
+    flag_offline: bool,
+    flag_post_run: Option<String>,
+    flag_prelude_file: Option<String>,
+    flag_quiet: bool,
+    flag_quiet_cargo: bool,
+    flag_recursion_guard: u32,
+    flag_reproducible_build: bool,
+    flag_rustc_flag: Vec<String>,
+    flag_env: Vec<String>,
+    flag_run_args_file: Option<String>,
+    flag_sanitizer: Option<String>,
+    flag_stdin_file: Option<String>,
+    flag_timings: bool,
+    flag_target: Option<String>,
+    flag_target_feature: Option<String>,
+    flag_template: Option<String>,
+    flag_toolchain: Option<String>,
+    flag_features: Vec<String>,
+    flag_features_all_deps: Option<String>,
+    flag_verify_exe: bool,
+    flag_watch: bool,
 }
 
 const USAGE: &'static str = "Usage:
-    cargo script [options] [--dep SPEC...] <script>
-    cargo script [options] [--dep SPEC...] --expr EXPR
-    cargo script [options] [--dep SPEC...] [--count] --loop CLOSURE
+    cargo script --run-artifact PATH [--] [<args>...]
+    cargo script [options] [-v...] [--dep SPEC...] [--dep-feature SPEC...] [--dep-override SPEC...] [--rustc-flag FLAG...] [--features FEATURE...] [--env VAR...] <script> [--] [<args>...]
+    cargo script [options] [-v...] [--dep SPEC...] [--dep-feature SPEC...] [--dep-override SPEC...] [--rustc-flag FLAG...] [--features FEATURE...] [--env VAR...] (--expr EXPR | --expr-file PATH)
+    cargo script [options] [-v...] [--dep SPEC...] [--dep-feature SPEC...] [--dep-override SPEC...] [--rustc-flag FLAG...] [--features FEATURE...] [--env VAR...] [--count] --loop CLOSURE
+    cargo script --stats
+    cargo script --list-cache
     cargo script --help
 
+If <script> is `-`, the script is read from stdin.
+
 Options:
     -h, --help              Show this message.
+    -v, --verbose           Show what cargo-script is doing.  Give it twice to
+                            show even more.  RUST_LOG, if set, takes precedence.
 
     --expr EXPR             Evaluate an expression and display the result.
+                            If EXPR is `-`, it is read from stdin.
+    --expr-file PATH        Evaluate the expression in the file at PATH, just
+                            as if it had been given with --expr.
+    --expr-out PATH         Write the output of --expr to PATH instead of
+                            stdout.
+    --pretty                Display the result of --expr using its pretty
+                            `Debug` output.
+    --json                  Display the result of --expr as JSON, using
+                            `serde_json`.  The result's type must implement
+                            `serde::Serialize`.
     --loop CLOSURE          Invoke a closure once for each line from stdin.
     --count                 Invoke the loop closure with two arguments: line,
                             and line number.
+    --split CHAR            Split the input to --loop on CHAR rather than on
+                            newlines.  CHAR can also be given as one of the
+                            escapes `\\0`, `\\t`, `\\n`, or `\\\\`.
 
     --build-only            Build the script, but don't run it.
+    --print-exe-path        Build the script, then print the path to its
+                            executable instead of running it.
+    --artifact-manifest PATH
+                            With --build-only, write a JSON description of the
+                            built executable to PATH.
+    --run-artifact PATH     Run the executable described by an artifact
+                            manifest, passing it any remaining arguments.
+    --cargo-subcommand NAME
+                            Generate the script's package, then run
+                            `cargo NAME` on it instead of building and running
+                            it.  This happens in a temporary folder, so nothing
+                            is kept in the cache.
+    --check                 Type-check the script with `cargo check`, without
+                            building or running it.  This also happens in a
+                            temporary folder.
+    --crate-type TYPE       Build the script as a library instead, then print
+                            the path to it.  TYPE can be one of `cdylib`,
+                            `dylib`, or `staticlib`.
+    --gen-pkg-only DIR      Write the script's package (its manifest and
+                            source) to DIR, rather than building and running
+                            it.  DIR must be empty, unless --force is given.
+    --manifest-only         Print the script's manifest, as it would be
+                            generated, rather than building and running it.
+                            Features enabled by --features-all-deps aren't
+                            included, since that needs Cargo to resolve the
+                            dependencies.
+    --warm                  Build the script if it isn't already cached, but
+                            don't run it.  Says whether it had to be built.
+                            Meant for filling the cache ahead of time.
+    --clean                 Remove the script's package from the cache, rather
+                            than building and running it.  Says whether there
+                            was anything to remove.
+    --dry-run               Say whether the script would be built or run from
+                            the cache, where, and with what dependencies and
+                            command, without doing any of it.
+    --tree                  Build the script, then show its dependency tree
+                            instead of running it.
+    --bin NAME              Build and run the binary called NAME, when the
+                            script's manifest declares more than one.
+    --run-all               Run every binary declared in the script's manifest
+                            one after the other, stopping at the first failure.
+                            No arguments are passed to them, though --env
+                            and --post-run still apply.
+    --run-tests-then-main   Run the script's tests first, and only run the
+                            script itself if they all pass.
+    --keep-going            With --run-all, keep running binaries after one
+                            fails.
+
+    --stats                 Show a summary of what's in the cache, including
+                            how many packages are old enough to be cleaned up.
+    --list-cache            List every package in the cache, along with what
+                            it was built from and how big it is.
+    --color WHEN            Whether to colour output, both ours and Cargo's.
+                            WHEN can be one of `auto`, `always`, or `never`.
+                            `never` also sets NO_COLOR for anything we run.
+                            [default: auto]
     --debug                 Build a debug executable, not an optimised one.
+    --release               Build an optimised executable.  This is the
+                            default, unless CARGO_SCRIPT_PROFILE is set to
+                            `debug`.
     --dep SPEC              Add an additional Cargo dependency.  Each SPEC can
-                            be either just the package name (which will assume
-                            the latest version) or a full `name=version` spec.
-    --force                 Force the script to be rebuilt.
+                            be either just the package name (which will use the
+                            version in CARGO_SCRIPT_DEP_<name> if set, or the
+                            latest version) or a full `name=version` spec.
+                            Instead of a version, `git:URL` or `path:PATH` can
+                            be used to depend on unpublished code.
+    --dep-feature SPEC      Enable a feature of a dependency given with --dep.
+                            SPEC is given as `name/feature`.
+    --dep-from PATH         Use the dependencies from the Cargo manifest at
+                            PATH, as if each had been given with --dep.
+    --dep-override SPEC     Force the version of a dependency, even an
+                            indirect one, using `[patch.crates-io]`.  SPEC is
+                            a full `name=version` spec.  Cargo will only accept
+                            a patch which points somewhere other than
+                            crates.io (*e.g.* a git repository, given as a
+                            table), and which is compatible with the version
+                            being replaced.
+    --deps-inline TOML      Merge a Cargo manifest fragment into the generated
+                            manifest, such as
+                            'dependencies = { time = \"0.1.25\" }'.
+    --edition YEAR          Build the script with the given Rust edition, such
+                            as `2018`, unless its manifest already sets one.
+    --env VAR               Set an environment variable for the script when it
+                            runs, but not while it's being built.  VAR is
+                            given as `KEY=VALUE`, and overrides anything set
+                            in the script's manifest.
+    --explain-cache-miss    If the script has to be rebuilt, write a JSON
+                            object to stderr saying why, including which
+                            metadata fields differ from the cached build.
+    --features FEATURE      Enable features when building the script.  This
+                            can be a comma-separated list, and can be given
+                            more than once.  Features of dependencies can be
+                            given as `dep/feature`.
+    --features-all-deps FEATURE
+                            Enable FEATURE on every dependency that has it.
+                            Dependencies without it are left alone.  This has
+                            to ask Cargo to resolve the dependencies first.
+    --force                 Force the script to be rebuilt, or overwrite a
+                            non-empty directory with --gen-pkg-only.
+    --max-cache-age AGE     Remove cached packages which haven't been built
+                            in AGE, rather than the default of a week.  AGE is
+                            a number followed by one of `s`, `m`, `h`, `d`, or
+                            `w`, *e.g.* `12h`.
+    --max-jobs N            Don't let Cargo run more than N jobs at once when
+                            building the script.
+    --no-cache              Build the script in a temporary folder, and remove
+                            it once the script has finished, rather than using
+                            the cache.  Nothing is written to the cache.
+    --no-edition-check      Don't check that the active Rust toolchain supports
+                            the edition requested by the script.
+    --offline               Stop Cargo from using the network when building
+                            the script.  Dependencies must already be available
+                            locally.
+    --post-run CMD          Run CMD with the system shell once the script has
+                            finished, whether it succeeded or not.  The script's
+                            exit code is in CARGO_SCRIPT_EXIT_CODE.  CMD is run
+                            as-is, so don't pass anything you don't trust.
+    --prelude-file PATH     Insert the contents of PATH at the top of the
+                            script's source.  Handy for common imports, macros
+                            and helper functions.
+    -q, --quiet             Only show the script's own output, and errors.
+                            This implies --quiet-cargo, and also silences our
+                            own warnings.
+    --quiet-cargo           Tell Cargo not to say anything while building,
+                            except for errors.
+    --recursion-guard LIMIT
+                            Refuse to run if scripts have invoked cargo-script
+                            more than LIMIT levels deep.  [default: 10]
+    --reproducible-build    Try to make the executable depend only on the
+                            script and options, not on where or when it was
+                            built.  Sets SOURCE_DATE_EPOCH (to 0, unless it is
+                            already set), strips the cache and Cargo paths from
+                            the executable, and turns off incremental builds.
+    --run-args-file PATH    Pass each line of PATH to the script as another
+                            argument, after any given on the command line.
+                            Blank lines are skipped.
+    --rustc-flag FLAG       Pass an extra flag to `rustc` when building the
+                            script, *e.g.* `-C target-cpu=native`.  Since the
+                            flags are passed using RUSTFLAGS, a FLAG can't
+                            contain spaces other than between arguments.
+    --sanitizer NAME        Build with a sanitizer enabled.  NAME can be one of
+                            `address`, `leak`, `memory`, or `thread`.  Requires
+                            a nightly toolchain.
+    --stdin-file PATH       Feed the contents of PATH to the script on stdin,
+                            rather than passing on our own stdin.  This works
+                            with --loop, too.
+    --target TRIPLE         Build the script for another target, such as
+                            `wasm32-unknown-unknown`.  Unless the executable
+                            can run on this machine, use --build-only.
+    --target-feature FEATURES
+                            Enable or disable target features when compiling
+                            the script, *e.g.* `+avx2,+fma`.
+    --timings               Once the script has finished, say how long it took
+                            to build, and to run.  This also works when the
+                            script is only built or checked.
+    --template NAME         Wrap --expr or --loop input with the template
+                            `templates/NAME.rs` from the config directory,
+                            instead of the built-in one.  `%%` in the template
+                            is replaced with the input.
+    --toolchain NAME        Build the script with a particular rustup
+                            toolchain, *e.g.* `nightly`.
+    --verify-exe            Before running a cached executable, check that its
+                            hash matches the one recorded when it was built.
+    --watch                 Keep running, and build and run the script again
+                            each time it changes.  Stop with Ctrl-C.
 ";
 
 fn main() {
-    env_logger::init().unwrap();
+    let args = match parse_args() {
+        Ok(args) => args,
+        Err(err) => {
+            println!("Error: {}", err);
+            std::process::exit(err.exit_code());
+        }
+    };
+
+    init_logging(args.flag_verbose, args.flag_quiet);
     info!("starting");
-    match try_main() {
+    info!("Arguments: {:?}", args);
+
+    // Anything we run inherits our environment, which takes care of both Cargo and the script itself.
+    if args.flag_color == Color::Never {
+        std::env::set_var("NO_COLOR", "1");
+    }
+    let color = args.flag_color.enabled();
+
+    let result = match args.flag_watch {
+        true => watch_script(args, color),
+        false => try_main(args)
+    };
+
+    match result {
         Ok(0) => (),
         Ok(code) => {
             std::process::exit(code);
         },
         Err(ref err) if err.is_human() => {
-            // TODO: output to stderr.
-            println!("Error: {}", err);
-            std::process::exit(1);
+            print_error(err, color);
+            std::process::exit(err.exit_code());
         },
         result @ Err(..) => {
             result.unwrap();
@@ -98,11 +352,343 @@ fn main() {
     }
 }
 
-fn try_main() -> Result<i32> {
-    let args: Args = docopt::Docopt::new(USAGE)
-        .and_then(|d| d.decode())
-        .unwrap_or_else(|e| e.exit());
-    info!("Arguments: {:?}", args);
+/**
+Reports an error which is the user's fault.
+*/
+fn print_error(err: &MainError, color: bool) {
+    // TODO: output to stderr.
+    match color {
+        true => println!("\x1b[1;31mError:\x1b[0m {}", err),
+        false => println!("Error: {}", err)
+    }
+}
+
+/**
+Runs the script, then runs it again every time the script file changes, for `--watch`.  This doesn't return unless something goes badly wrong; errors which are the script's fault (such as it failing to build) are reported, and then we go back to waiting for it to change.
+
+To avoid needing anything platform-specific, this just polls the script's modification time.
+*/
+fn watch_script(args: Args, color: bool) -> Result<i32> {
+    let script = match args.arg_script {
+        Some(ref script) if script != "-" => script.clone(),
+        _ => try!(Err((Blame::Human, "--watch can only be used with a script file, not --expr, --loop, or stdin")))
+    };
+    let (script_path, _) = try!(find_script(&script).ok_or((Blame::Human, "could not find script")));
+    let modified = || fs::metadata(&script_path).map(|md| md.modified()).ok();
+
+    loop {
+        // Anything which changes while the script is being built or run should trigger another go.
+        let last_modified = modified();
+        match try_main(args.clone()) {
+            Ok(code) => info!("script exited with {}", code),
+            Err(ref err) if err.is_human() => print_error(err, color),
+            Err(err) => return Err(err)
+        }
+
+        if !args.flag_quiet {
+            let _ = writeln!(std::io::stderr(), "watching {} for changes...", script_path.display());
+        }
+        while !watch_should_rerun(last_modified, modified()) {
+            std::thread::sleep_ms(consts::WATCH_POLL_MS);
+        }
+    }
+}
+
+/**
+Decides whether `--watch` should run the script again, given its modification time when it last ran, and its modification time now.  While the script is missing (as it can be for a moment while an editor saves it), there's nothing to run, so we keep waiting for it to come back.
+*/
+fn watch_should_rerun(last_modified: Option<u64>, modified: Option<u64>) -> bool {
+    match modified {
+        Some(_) => modified != last_modified,
+        None => false
+    }
+}
+
+/**
+Sets up logging.  If `RUST_LOG` is set, it's used as-is; otherwise, `verbosity` (how many times `--verbose` was given) picks the level.  Warnings are shown by default, since that's how the library tells the user about things, unless `quiet`.
+*/
+fn init_logging(verbosity: usize, quiet: bool) {
+    use log::{LogLevel, LogLevelFilter, LogRecord};
+
+    let mut builder = env_logger::LogBuilder::new();
+    builder.format(|record: &LogRecord| match record.level() {
+        LogLevel::Warn => format!("warning: {}", record.args()),
+        level => format!("{}:{}: {}", level, record.location().module_path(), record.args())
+    });
+    match std::env::var("RUST_LOG") {
+        Ok(filters) => {
+            builder.parse(&filters);
+        },
+        Err(_) => {
+            let level = match verbosity {
+                0 if quiet => LogLevelFilter::Error,
+                0 => LogLevelFilter::Warn,
+                1 => LogLevelFilter::Info,
+                _ => LogLevelFilter::Debug
+            };
+            builder.filter(None, level);
+        }
+    }
+    builder.init().unwrap();
+}
+
+/**
+Works out which arguments to parse, given the ones we were run with.
+
+Normally, Cargo runs us as `cargo-script script ...`.  However, a script can also be run directly using a hashbang line like `#!/usr/bin/env cargo-script`, in which case we get `cargo-script <script> <args>...`.  That gets turned into `cargo-script script <script> -- <args>...`, so that none of the script's arguments are mistaken for ours.
+*/
+fn get_argv(mut argv: Vec<String>) -> Vec<String> {
+    use std::fs::PathExt;
+
+    let direct = argv.len() >= 2
+        && argv[1] != "script"
+        && Path::new(&argv[0]).file_stem().and_then(|s| s.to_str()) == Some("cargo-script")
+        && Path::new(&argv[1]).is_file();
+    if direct {
+        info!("invoked directly on {:?}", argv[1]);
+        argv.insert(1, "script".into());
+        argv.insert(3, "--".into());
+    }
+    argv
+}
+
+/**
+Parses the command line into `Args`, merging in any flags from the script's directive line (see `find_script_directive`).
+
+Flags given on the command line take precedence over the directive, except for those which can be given more than once: those from the directive come first, followed by the command line's.
+*/
+fn parse_args() -> Result<Args> {
+    let argv = get_argv(std::env::args().collect());
+    let parse = |argv: Vec<String>| docopt::Docopt::new(USAGE)
+        .map(|d| d.argv(argv.into_iter()))
+        .and_then(|d| d.parse());
+
+    let mut argv_map = parse(argv.clone()).unwrap_or_else(|e| e.exit());
+
+    // Flags from anywhere other than the command line are parsed on their own, as though they'd been given right before the script (or stdin, if there isn't one).  We also need to know what the defaults are, so that we can tell which flags the command line actually set.
+    let script = argv_map.get_str("<script>").to_owned();
+    let script_argv = |flags: Vec<String>| {
+        let mut script_argv = vec![argv[0].clone(), "script".into()];
+        script_argv.extend(flags);
+        script_argv.push(if script == "" { "-".into() } else { script.clone() });
+        script_argv
+    };
+    let default_map = try!(parse(script_argv(vec![]))
+        .map_err(|err| format!("could not parse default arguments: {}", err)));
+
+    // The directive goes first, so that it takes precedence over the project config.
+    if script != "" && script != "-" {
+        if let Some((line_no, directive)) = try!(find_script_directive(&script)) {
+            let flags = directive.split_whitespace().map(String::from).collect();
+            let dir_map = try!(parse(script_argv(flags))
+                .map_err(|err| (Blame::Human, format!(
+                    "invalid `cargo-script:` directive on line {} of {}: {}", line_no, script, err))));
+            merge_flags(&mut argv_map, &dir_map, &default_map);
+        }
+    }
+
+    if let Some((config_path, flags)) = try!(find_project_config(&try!(std::env::current_dir()))) {
+        let config_map = try!(parse(script_argv(flags))
+            .map_err(|err| (Blame::Human, format!(
+                "invalid settings in {}: {}", config_path.display(), err))));
+        merge_flags(&mut argv_map, &config_map, &default_map);
+    }
+
+    Ok(argv_map.decode().unwrap_or_else(|e| e.exit()))
+}
+
+/**
+Merges flags from somewhere other than the command line (a script's directive, or the project config) into `argv_map`, which takes precedence.  `default_map` is what the flags are when nothing is given.
+
+Lists are combined, with the merged items going first.  Anything else is only taken if `argv_map` still has the default value.  `--debug` and `--release` are two halves of the same setting, so if either one is already set, neither is taken.
+*/
+fn merge_flags(argv_map: &mut docopt::ArgvMap, from_map: &docopt::ArgvMap, default_map: &docopt::ArgvMap) {
+    use docopt::Value;
+
+    let profile_set = argv_map.get_bool("--debug") || argv_map.get_bool("--release");
+
+    for (key, from_val) in from_map.map.iter() {
+        // Only flags; the script and its arguments only ever come from the command line.
+        if !key.starts_with("-") {
+            continue;
+        }
+        if profile_set && (key == "--debug" || key == "--release") {
+            continue;
+        }
+
+        let merged = match (argv_map.map.find(key), from_val) {
+            (Some(&Value::List(ref cmd_items)), &Value::List(ref from_items)) => {
+                let mut items = from_items.clone();
+                items.extend(cmd_items.iter().cloned());
+                Value::List(items)
+            },
+            (cmd_val, _) if cmd_val == default_map.map.find(key) => from_val.clone(),
+            _ => continue
+        };
+        argv_map.map.swap(key.clone(), merged);
+    }
+}
+
+/**
+Looks for a project config file (`.cargo-script.toml`) in `start_dir`, or the nearest parent directory which has one, and returns its path along with the flags it stands for.  The config can contain:
+
+- `deps`: a list of dependencies, as given to `--dep`.  A `path:` dependency is relative to the directory the config is in.
+- `profile`: either `"debug"` or `"release"`.
+- `rustc-flags`: a list of flags, as given to `--rustc-flag`.
+
+Only the nearest config is used; they aren't combined.
+*/
+fn find_project_config(start_dir: &Path) -> Result<Option<(PathBuf, Vec<String>)>> {
+    use std::fs::PathExt;
+
+    let mut config_path = None;
+    let mut dir = Some(start_dir);
+    while let Some(d) = dir {
+        let path = d.join(consts::PROJECT_CONFIG_FILE);
+        if path.is_file() {
+            config_path = Some(path);
+            break;
+        }
+        dir = d.parent();
+    }
+    let config_path = match config_path {
+        Some(config_path) => config_path,
+        None => return Ok(None)
+    };
+    info!("config_path: {:?}", config_path);
+
+    let config_str = try!(read_user_file(&config_path));
+    let what = config_path.display().to_string();
+    let config = try!(parse_user_manifest(&what, &config_str));
+
+    let mut flags = vec![];
+    for (key, value) in &config {
+        let flag = match &**key {
+            "deps" => "--dep",
+            "rustc-flags" => "--rustc-flag",
+            "profile" => {
+                match value.as_str() {
+                    Some("debug") => flags.push("--debug".into()),
+                    Some("release") => flags.push("--release".into()),
+                    _ => try!(Err((Blame::Human,
+                        format!("`profile` in {} must be \"debug\" or \"release\"", what))))
+                }
+                continue;
+            },
+            _ => try!(Err((Blame::Human, format!("unknown setting `{}` in {}", key, what))))
+        };
+
+        let items = match value.as_slice() {
+            Some(items) => items,
+            None => try!(Err((Blame::Human, format!("`{}` in {} must be a list of strings", key, what))))
+        };
+        for item in items {
+            match item.as_str() {
+                Some(item) => {
+                    flags.push(flag.into());
+                    flags.push(match flag {
+                        "--dep" => resolve_config_dep(item, config_path.parent().unwrap_or(Path::new("."))),
+                        _ => item.into()
+                    });
+                },
+                None => try!(Err((Blame::Human, format!("`{}` in {} must be a list of strings", key, what))))
+            }
+        }
+    }
+
+    Ok(Some((config_path, flags)))
+}
+
+/**
+Makes a `path:` dependency from the project config relative to `config_dir`, rather than to wherever `cargo script` happens to be run from.  Any other dependency is left as it is.
+*/
+fn resolve_config_dep(dep: &str, config_dir: &Path) -> String {
+    match dep.find('=') {
+        Some(i) if dep[i + 1..].starts_with("path:") && dep.len() > i + 6 => {
+            format!("{}path:{}", &dep[..i + 1], config_dir.join(&dep[i + 6..]).display())
+        },
+        _ => dep.into()
+    }
+}
+
+/**
+Looks for a directive line in the named script, giving extra flags to use when running it, such as:
+
+```text
+//! cargo-script: --debug --dep time
+```
+
+This has to be the first line, or the second if the first is a hashbang.  Flags are separated by whitespace; there's no quoting.  Returns the line number and the flags, if there's a directive.  If there's no such script, that's left for `try_main` to complain about.
+*/
+fn find_script_directive(script: &str) -> Result<Option<(usize, String)>> {
+    let file = match find_script(script) {
+        Some((_, file)) => file,
+        None => return Ok(None)
+    };
+
+    for (i, line) in std::io::BufReader::new(file).lines().enumerate().take(2) {
+        let line = try!(line);
+        if i == 0 && line.starts_with("#!") && !line.starts_with("#![") {
+            continue;
+        }
+        if let Some(flags) = strip_script_directive(&line) {
+            return Ok(Some((i + 1, flags.to_owned())));
+        }
+        break;
+    }
+
+    Ok(None)
+}
+
+/**
+Works out the settings for building the script.  `--quiet` means nothing should be said that doesn't have to be, so it quiets Cargo as well.
+*/
+fn build_settings(args: &Args) -> BuildSettings {
+    BuildSettings {
+        check_edition: !args.flag_no_edition_check,
+        jobs: args.flag_max_jobs,
+        color: args.flag_color,
+        quiet_cargo: args.flag_quiet_cargo || args.flag_quiet,
+        quiet: args.flag_quiet,
+        offline: args.flag_offline,
+        write_metadata: !args.flag_no_cache,
+    }
+}
+
+fn try_main(args: Args) -> Result<i32> {
+    // Scripts which themselves use `cargo script` could end up recursing forever; we'd rather not take the machine down with them.
+    let depth = get_recursion_depth();
+    info!("depth: {}", depth);
+    try!(check_recursion_depth(depth, args.flag_recursion_guard));
+
+    // Worked out up front, since bits of `args` get moved out along the way.
+    let settings = build_settings(&args);
+
+    if let Some(ref art_path) = args.flag_run_artifact {
+        return run_artifact(art_path, &args.arg_args);
+    }
+
+    if args.flag_stats {
+        return print_cache_stats();
+    }
+
+    if args.flag_list_cache {
+        return print_cache_list();
+    }
+
+    if args.flag_no_cache && (args.flag_warm || args.flag_clean || args.flag_build_only
+            || args.flag_print_exe_path || args.flag_crate_type.is_some()) {
+        try!(Err((Blame::Human, "--no-cache cannot be used with --warm, --clean, --build-only, \
+            --print-exe-path, or --crate-type, since nothing is kept")));
+    }
+
+    if args.flag_dry_run && args.flag_no_cache {
+        try!(Err((Blame::Human, "--dry-run cannot be used with --no-cache, since there's no cache to check")));
+    }
+
+    if args.flag_artifact_manifest.is_some() && !args.flag_build_only {
+        try!(Err((Blame::Human, "--artifact-manifest can only be used with --build-only")));
+    }
 
     // Take the arguments and work out what our input is going to be.  Primarily, this gives us the content, a user-friendly name, and a cache-friendly ID.
     // These three are just storage for the borrows we'll actually use.
@@ -110,55 +696,110 @@ fn try_main() -> Result<i32> {
     let script_path: PathBuf;
     let content: String;
 
-    let input = match (args.arg_script.as_ref(), args.flag_expr, args.flag_loop) {
+    // An expression from a file (or stdin) is otherwise no different from one given directly, so that they share cache entries.  Only `--expr -` means stdin; a file containing `-` is just a bad expression.
+    let expr = match (args.flag_expr, args.flag_expr_file) {
+        (Some(_), Some(_)) => try!(Err((Blame::Human, "cannot use both --expr and --expr-file"))),
+        (Some(ref expr), None) if expr == "-" => {
+            let mut expr = String::new();
+            try!(std::io::stdin().read_to_string(&mut expr));
+            Some(trim_expr(expr))
+        },
+        (Some(expr), None) => Some(expr),
+        (None, Some(expr_path)) => Some(trim_expr(try!(read_user_file(&expr_path)))),
+        (None, None) => None
+    };
+
+    let input = match (args.arg_script.as_ref(), expr, args.flag_loop) {
+        (Some(script), None, None) if script == "-" => {
+            let mut body = String::new();
+            try!(std::io::stdin().read_to_string(&mut body));
+            content = body;
+            Input::Stdin(&content)
+        },
         (Some(script), None, None) => {
-            let (path, mut file) = try!(find_script(script).ok_or("could not find script"));
+            let (path, mut file) = try!(find_script(script).ok_or((Blame::Human, "could not find script")));
 
-            script_name = path.file_stem()
-                .map(|os| os.to_string_lossy().into_owned())
-                .unwrap_or("unknown".into());
+            let (name, path) = try!(resolve_script(&path));
+            script_name = name;
+            script_path = path;
 
             let mut body = String::new();
             try!(file.read_to_string(&mut body));
 
             let mtime = file.metadata().map(|md| md.modified()).unwrap_or(0);
-
-            script_path = try!(std::env::current_dir()).join(path);
             content = body;
 
             Input::File(&script_name, &script_path, &content, mtime)
         },
         (None, Some(expr), None) => {
             content = expr;
-            Input::Expr(&content)
+            let style = match (args.flag_pretty, args.flag_json) {
+                (false, false) => ExprStyle::Debug,
+                (true, false) => ExprStyle::Pretty,
+                (false, true) => ExprStyle::Json,
+                (true, true) => try!(Err((Blame::Human, "cannot use both --pretty and --json")))
+            };
+            Input::Expr(&content, style)
         },
         (None, None, Some(loop_)) => {
+            // The closure has to come from *somewhere* other than stdin, since that's where the lines it's fed come from.
+            if loop_ == "-" {
+                try!(Err((Blame::Human,
+                    "cannot read --loop closure from stdin; stdin is used for the loop's input")));
+            }
+            let sep = match args.flag_split {
+                Some(ref sep) => try!(parse_loop_separator(sep)),
+                None => b'\n'
+            };
             content = loop_;
-            Input::Loop(&content, args.flag_count)
+            Input::Loop(&content, args.flag_count, sep)
         },
         _ => try!(Err((Blame::Human,
             "cannot specify more than one of <script>, --expr, or --loop")))
     };
     info!("input: {:?}", input);
 
+    // Some options only make sense for some kinds of input.
+    let (is_script, is_expr, is_loop) = match input {
+        Input::File(..) | Input::Stdin(..) => (true, false, false),
+        Input::Expr(..) => (false, true, false),
+        Input::Loop(..) => (false, false, true),
+    };
+    try!(only_with(args.flag_warm, is_script, "--warm can only be used with a script"));
+    try!(only_with(args.flag_pretty, is_expr, "--pretty can only be used with --expr"));
+    try!(only_with(args.flag_json, is_expr, "--json can only be used with --expr"));
+    try!(only_with(args.flag_split.is_some(), is_loop, "--split can only be used with --loop"));
+    try!(only_with(args.flag_expr_out.is_some(), is_expr, "--expr-out can only be used with --expr"));
+    try!(only_with(args.flag_template.is_some(), is_expr || is_loop,
+        "--template can only be used with --expr or --loop"));
+
     /*
     Sort out the dependencies.  We want to do a few things:
 
     - Sort them so that they hash consistently.
     - Check for duplicates.
-    - Expand `pkg` into `pkg=*`.
+    - Expand `pkg` into `pkg=VERSION`, where `VERSION` is pinned in the environment, or `*` if not.
+    - Add any features given with `--dep-feature`.
     */
     let deps = {
         use std::collections::HashMap;
         use std::collections::hash_map::Entry::{Occupied, Vacant};
 
+        // Anything imported from another manifest gets checked for conflicts just like the rest.
+        let mut dep_specs = match args.flag_dep_from {
+            Some(ref mani_path) => try!(read_deps_from(Path::new(mani_path))),
+            None => vec![]
+        };
+        dep_specs.extend(args.flag_dep);
+
+        // `--json` needs `serde_json`, unless the user has already said which one to use.
+        if args.flag_json && !dep_specs.iter().any(|d| d.splitn(2, '=').next() == Some("serde_json")) {
+            dep_specs.push("serde_json".into());
+        }
+
         let mut deps: HashMap<String, String> = HashMap::new();
-        for dep in args.flag_dep {
-            // Append '=*' if it needs it.
-            let dep = match dep.find('=') {
-                Some(_) => dep,
-                None => dep + "=*"
-            };
+        for dep in dep_specs {
+            let dep = with_dep_version(dep);
 
             let mut parts = dep.splitn(2, '=');
             let name = parts.next().expect("dependency is missing name");
@@ -173,9 +814,11 @@ fn try_main() -> Result<i32> {
                 try!(Err((Blame::Human, "cannot have empty dependency version")));
             }
 
+            let version = try!(expand_dep_source(name, version));
+
             match deps.entry(name.into()) {
                 Vacant(ve) => {
-                    ve.insert(version.into());
+                    ve.insert(version);
                 },
                 Occupied(oe) => {
                     // This is *only* a problem if the versions don't match.  We won't try to do anything clever in terms of upgrading or resolving or anything... exact match or go home.
@@ -189,6 +832,9 @@ fn try_main() -> Result<i32> {
             }
         }
 
+        // Features of individual dependencies are folded into their specs, so they end up in the manifest (and the package ID) along with everything else.
+        try!(apply_dep_features(&mut deps, &args.flag_dep_feature));
+
         // Sort and turn into a regular vec.
         let mut deps: Vec<(String, String)> = deps.into_iter().collect();
         deps.sort();
@@ -196,441 +842,1250 @@ fn try_main() -> Result<i32> {
     };
     info!("deps: {:?}", deps);
 
-    // Work out what to do.
-    let (action, pkg_path, meta) = cache_action_for(&input, args.flag_debug, deps);
-    info!("action: {:?}", action);
-    info!("pkg_path: {:?}", pkg_path);
-    info!("meta: {:?}", meta);
-
-    // Compile if we need it.
-    if action == CacheAction::Compile || args.flag_force {
-        info!("compiling...");
-        try!(compile(&input, &meta, &pkg_path));
-    }
-
-    if args.flag_build_only {
-        return Ok(0);
+    // Sanitizers are nightly-only, and need an explicit target so that the flags don't also get applied to build scripts.
+    if args.flag_target.as_ref().map(|t| t.trim() == "").unwrap_or(false) {
+        try!(Err((Blame::Human, "--target cannot be empty")));
     }
 
-    // Run it!
-    let exe_path = get_exe_path(&input, &pkg_path, &meta);
-    info!("executing {:?}", exe_path);
-    Ok(try!(Command::new(exe_path).status()
-        .map(|st| st.code().unwrap_or(1))))
-}
-
-/**
-Compile a package from the input.
+    let (sanitizer, target) = match args.flag_sanitizer {
+        Some(sanitizer) => {
+            if !consts::SANITIZERS.contains(&&*sanitizer) {
+                try!(Err((Blame::Human, format!("unknown sanitizer '{}'", sanitizer))));
+            }
 
-Why take `PackageMetadata`?  To ensure that any information we need to depend on for compilation *first* passes through `cache_action_for` *and* is less likely to not be serialised with the rest of the metadata.
-*/
-fn compile<P>(input: &Input, meta: &PackageMetadata, pkg_path: P) -> Result<()>
-where P: AsRef<Path> {
-    let pkg_path = pkg_path.as_ref();
+            let toolchain = args.flag_toolchain.as_ref().map(|s| &**s);
+            let release = try!(get_rustc_version_info(toolchain, "release"));
+            if !(release.contains("nightly") || release.contains("dev")) {
+                try!(Err((Blame::Human,
+                    format!("sanitizers require a nightly toolchain, found {}", release))));
+            }
 
-    let (mani_str, script_str) = try!(split_input(input, &meta.deps));
+            // Sanitizers need an explicit target, even if it's just the host.
+            let target = match args.flag_target {
+                Some(target) => target,
+                None => try!(get_rustc_version_info(toolchain, "host"))
+            };
+            (Some(sanitizer), Some(target))
+        },
+        None => (None, args.flag_target)
+    };
 
-    try!(fs::create_dir_all(pkg_path));
+    if let Some(ref features) = args.flag_target_feature {
+        let valid = features.split(',').all(|f|
+            (f.starts_with("+") || f.starts_with("-")) && f.len() > 1);
+        if !valid {
+            try!(Err((Blame::Human,
+                format!("invalid target features '{}'; expected a comma-separated list \
+                    like `+avx2,-fma`", features))));
+        }
+    }
 
-    let mani_path = {
-        let mani_path = pkg_path.join("Cargo.toml");
-        let mut mani_f = try!(fs::File::create(&mani_path));
-        try!(write!(&mut mani_f, "{}", mani_str));
-        try!(mani_f.flush());
-        mani_path
+    let max_cache_age = match args.flag_max_cache_age {
+        Some(ref age) => try!(parse_duration(age)),
+        None => consts::MAX_CACHE_AGE_MS
     };
 
-    {
-        let script_path = pkg_path.join(input.safe_name()).with_extension("rs");
-        let mut script_f = try!(fs::File::create(script_path));
-        try!(write!(&mut script_f, "{}", script_str));
-        try!(script_f.flush());
+    if args.flag_max_jobs == Some(0) {
+        try!(Err((Blame::Human, "--max-jobs must be at least 1")));
     }
 
-    // *bursts through wall* It's Cargo Time!
-    let mut cmd = Command::new("cargo");
-    cmd.arg("build")
-        .arg("--manifest-path")
-        .arg(&*mani_path.to_string_lossy());
-
-    if !meta.debug {
-        cmd.arg("--release");
+    if let Some(ref edition) = args.flag_edition {
+        if !consts::EDITION_MIN_RUST_VERSIONS.iter().any(|&(ed, _)| ed == &**edition) {
+            try!(Err((Blame::Human, format!("unknown edition '{}'", edition))));
+        }
     }
 
-    try!(cmd.status().map_err(|e| Into::<MainError>::into(e)).and_then(|st|
-        match st.code() {
-            Some(0) => Ok(()),
-            Some(st) => Err(format!("cargo failed with status {}", st).into()),
-            None => Err("cargo failed".into())
-        }));
+    if args.flag_bin.is_some() && (args.flag_run_all || args.flag_crate_type.is_some()) {
+        try!(Err((Blame::Human, "--bin cannot be used with --run-all or --crate-type")));
+    }
 
-    // Write out metadata *now*.  Remember that we check the timestamp in the metadata, *not* on the executable.
-    try!(write_pkg_metadata(pkg_path, meta));
+    // There's only the one file, so there's no sensible way to share it out between several binaries.
+    if args.flag_stdin_file.is_some() && args.flag_run_all {
+        try!(Err((Blame::Human, "--stdin-file cannot be used with --run-all")));
+    }
 
-    Ok(())
-}
+    if let Some(ref crate_type) = args.flag_crate_type {
+        if !consts::CRATE_TYPES.contains(&&**crate_type) {
+            try!(Err((Blame::Human, format!("unsupported crate type '{}'", crate_type))));
+        }
+    }
 
-/**
-Splits input into a complete Cargo manifest and unadultered Rust source.
-*/
-fn split_input(input: &Input, deps: &[(String, String)]) -> Result<(String, String)> {
-    let (part_mani, source, template) = match *input {
-        Input::File(_, _, content, _) => {
-            /*
-            We need to parse any partial manifest embedded in the content.  The only problem with this is that we *will not* assume the input is correctly formed, or that we've been passed a file that even *has* an embedded manifest; *i.e.* we might have been run with a plain Rust source file.
+    // These are much simpler than the dependencies, since there's no sensible default version.
+    let dep_overrides = {
+        let mut overrides = vec![];
+        for spec in args.flag_dep_override {
+            let mut parts = spec.splitn(2, '=');
+            let name = parts.next().unwrap_or("");
+            let version = match parts.next() {
+                Some(version) if name != "" && version != "" => version,
+                _ => try!(Err((Blame::Human,
+                    format!("invalid dependency override '{}'; expected `name=version`", spec))))
+            };
+            overrides.push((name.to_owned(), version.to_owned()));
+        }
+        overrides.sort();
+        overrides
+    };
+    info!("dep_overrides: {:?}", dep_overrides);
+
+    try!(check_rustc_flags(&args.flag_rustc_flag));
+
+    // These only matter when the script runs, so they stay out of the metadata.
+    let run_env = {
+        let mut run_env = vec![];
+        for var in &args.flag_env {
+            let mut parts = var.splitn(2, '=');
+            match (parts.next(), parts.next()) {
+                (Some(key), Some(value)) if key != "" => run_env.push((key.to_owned(), value.to_owned())),
+                _ => try!(Err((Blame::Human,
+                    format!("invalid environment variable '{}'; expected `KEY=VALUE`", var))))
+            }
+        }
+        run_env
+    };
 
-            First, we look for and discard a hashbang, if present.
+    // Like the environment, these only matter when the script runs, so they don't go anywhere near the metadata.
+    let run_args = {
+        let mut run_args = args.arg_args.clone();
+        if let Some(ref args_path) = args.flag_run_args_file {
+            let args_str = try!(read_user_file(args_path));
+            run_args.extend(args_str.lines_any()
+                .filter(|line| line.trim() != "")
+                .map(|line| line.to_owned()));
+        }
+        run_args
+    };
 
-            Next, we look for something which indicates the end of the embedded manifest.  *Officially*, this is a line which contains nothing but whitespace and *at least* three hyphens.  In *truth*, we will also look for anything that looks like Rust code.
+    // Open this now, so that we find out it's missing *before* building anything.
+    let run_stdin = match args.flag_stdin_file {
+        Some(ref in_path) => Some(try!(fs::File::open(in_path).map_err(|err| (Blame::Human, err)))),
+        None => None
+    };
 
-            Specifically, we check for a line starting with any of the strings in `SPLIT_MARKERS`.  This should *hopefully* cover every possible valid Rust program.
+    // Sorted, so that they hash consistently.
+    let features = {
+        let mut features: Vec<String> = args.flag_features.iter()
+            .flat_map(|f| f.split(|c: char| c == ',' || c.is_whitespace()))
+            .filter(|f| *f != "")
+            .map(|f| f.to_owned())
+            .collect();
+        features.sort();
+        features.dedup();
+        features
+    };
+    info!("features: {:?}", features);
 
-            Once we've done that, we just chop the script content up in the appropriate places.
-            */
-            let mut lines = content.lines_any().peekable();
+    let env_profile = std::env::var(consts::PROFILE_ENV).ok();
+    let debug = try!(resolve_profile(args.flag_debug, args.flag_release, env_profile.as_ref().map(|p| &**p)));
 
-            let skip = if let Some(line) = lines.peek() {
-                if line.starts_with("#!") && !line.starts_with("#![") {
-                    // This is a hashbang; toss it.
-                    true
-                } else {
-                    false
-                }
-            } else {
-                false
-            };
+    let template = match args.flag_template {
+        Some(ref name) => try!(load_template(name, args.flag_quiet)),
+        None => None
+    };
 
-            if skip { lines.next(); }
-
-            let mut manifest_end = None;
-            let mut source_start = None;
-
-            for line in lines {
-                // Did we get a dash separator?
-                let mut dashes = 0;
-                if line.chars().all(|c| {
-                    if c == '-' { dashes += 1 }
-                    c.is_whitespace() || c == '-'
-                }) && dashes >= 3 {
-                    info!("splitting because of dash divider in line {:?}", line);
-                    manifest_end = Some(&line[0..0]);
-                    source_start = Some(&line[line.len()..]);
-                    break;
-                }
+    let prelude = match args.flag_prelude_file {
+        Some(ref prelude_path) => {
+            let prelude = try!(read_user_file(prelude_path));
 
-                // Ok, it's-a guessin' time!  Yes, this is *evil*.
-                const SPLIT_MARKERS: &'static [&'static str] = &[
-                    "//", "/*", "#![", "#[", "pub",
-                    "extern", "use", "mod", "type",
-                    "struct", "enum", "fn", "impl",
-                    "static", "const",
-                ];
-
-                let line_trimmed = line.trim_left();
-
-                for marker in SPLIT_MARKERS {
-                    if line_trimmed.starts_with(marker) {
-                        info!("splitting because of marker '{:?}'", marker);
-                        manifest_end = Some(&line[0..]);
-                        source_start = Some(&line[0..]);
-                        break;
-                    }
-                }
+            // The script (or the template wrapping it) already has one of these.
+            if prelude.lines().any(|l| l.trim_left().starts_with("fn main")) {
+                try!(Err((Blame::Human, "the prelude file must not define `fn main`")));
             }
-
-            let (manifest, source) = match (manifest_end, source_start) {
-                (Some(me), Some(ss)) => {
-                    (&content[..content.subslice_offset(me)],
-                        &content[content.subslice_offset(ss)..])
-                },
-                _ => try!(Err("could not locate start of Rust source in script"))
-            };
-
-            // Hooray!
-            (manifest, source, consts::FILE_TEMPLATE)
-        },
-        Input::Expr(content) => ("", content, consts::EXPR_TEMPLATE),
-        Input::Loop(content, count) => {
-            let templ = if count { consts::LOOP_COUNT_TEMPLATE } else { consts::LOOP_TEMPLATE };
-            ("", content, templ)
+            Some(prelude)
         },
+        None => None
     };
 
-    let source = template.replace("%%", source);
+    // Construct input metadata.
+    let input_meta = {
+        let (path, mtime) = match input {
+            Input::File(_, path, _, mtime)
+                => (Some(path.to_string_lossy().into_owned()), Some(mtime)),
+            Input::Stdin(..)
+            | Input::Expr(..)
+            | Input::Loop(..)
+                => (None, None)
+        };
+        PackageMetadata {
+            path: path,
+            modified: mtime,
+            debug: debug,
+            deps: deps,
+            dep_overrides: dep_overrides,
+            deps_inline: args.flag_deps_inline,
+            prelude: prelude,
+            template: template,
+            sanitizer: sanitizer,
+            target: target,
+            target_features: args.flag_target_feature,
+            features: features,
+            features_all_deps: args.flag_features_all_deps,
+            crate_type: args.flag_crate_type,
+            bin: args.flag_bin,
+            edition: args.flag_edition,
+            reproducible: args.flag_reproducible_build,
+            toolchain: args.flag_toolchain,
+            rustc_flags: args.flag_rustc_flag,
+            exe_size: None,
+            exe_hash: None,
+            exe_path: None,
+            lock_hash: None,
+        }
+    };
+    info!("input_meta: {:?}", input_meta);
 
-    info!("part_mani: {:?}", part_mani);
-    info!("source: {:?}", source);
+    // Say what would happen, without doing any of it.  This goes before anything which actually touches the cache.
+    if args.flag_dry_run {
+        let id = try!(input.compute_id(&input_meta));
+        let (action, pkg_path, meta) = try!(cache_action_for(&input, input_meta, false, args.flag_verify_exe));
+
+        // Until it's built, the package might not even exist, so fall back to where the executable usually goes.
+        let exe_path = get_exe_path(&input, &pkg_path, &meta)
+            .unwrap_or_else(|_| get_bin_path(&pkg_path, &meta, &input.safe_name()));
+        let mut cmd = Command::new(exe_path);
+        cmd.args(&run_args);
+
+        let deps: Vec<String> = meta.deps.iter()
+            .map(|&(ref name, ref ver)| format!("{}={}", name, ver))
+            .collect();
+        println!("action: {:?}", action);
+        println!("id: {}", id.to_string_lossy());
+        println!("pkg_path: {}", pkg_path.display());
+        println!("deps: {}", deps.join(", "));
+        println!("command: {:?}", cmd);
+        return Ok(0);
+    }
 
-    let part_mani = try!(toml::Parser::new(part_mani).parse()
-        .ok_or("could not parse embedded manifest"));
-    info!("part_mani: {:?}", part_mani);
+    if args.flag_manifest_only {
+        let (mani_str, _) = try!(split_input(&input, &input_meta, &settings));
+        print!("{}", mani_str);
+        return Ok(0);
+    }
 
-    // It's-a mergin' time!
-    let def_mani = try!(default_manifest(input));
-    let dep_mani = try!(deps_manifest(deps));
+    if args.flag_clean {
+        let pkg_path = try!(get_cache_path()).join(try!(input.compute_id(&input_meta)));
+        info!("cleaning {:?}", pkg_path);
+        let status = match fs::metadata(&pkg_path) {
+            Ok(_) => {
+                try!(fs::remove_dir_all(&pkg_path));
+                "removed"
+            },
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => "not cached",
+            Err(err) => return Err(err.into())
+        };
+        println!("{}: {}", input_meta.path.as_ref().map(|s| &**s).unwrap_or("<script>"), status);
+        return Ok(0);
+    }
 
-    let mani = try!(merge_manifest(def_mani, part_mani));
-    let mani = try!(merge_manifest(mani, dep_mani));
-    info!("mani: {:?}", mani);
+    if let Some(ref gen_path) = args.flag_gen_pkg_only {
+        let gen_path = Path::new(gen_path);
+        let has_entries = match fs::read_dir(gen_path) {
+            Ok(mut entries) => entries.next().is_some(),
+            Err(ref err) if err.kind() == std::io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err.into())
+        };
+        if has_entries && !args.flag_force {
+            try!(Err((Blame::Human, format!(
+                "{:?} is not empty; use --force to write the package there anyway", gen_path))));
+        }
+        let mani_path = try!(generate_package(&input, &input_meta, gen_path, &settings));
+        info!("generated {:?}", mani_path);
+        return Ok(0);
+    }
 
-    let mani_str = format!("{}", toml::Value::Table(mani));
-    info!("mani_str: {}", mani_str);
+    // Cargo extensions and `cargo check` don't leave us an executable, so there's nothing worth caching; they get a temporary folder, just like `--no-cache`.
+    let temp_pkg = args.flag_no_cache || args.flag_cargo_subcommand.is_some() || args.flag_check;
 
-    Ok((mani_str, source))
-}
+    // Work out what to do.  Without the cache, that's always "build it somewhere temporary".
+    let (action, pkg_path, meta) = if temp_pkg {
+        (CacheAction::Compile, try!(make_temp_pkg_dir(&input)), input_meta)
+    } else {
+        // Get rid of anything in the cache which can't possibly be used again, but don't let that stop the script from running.
+        if let Err(err) = clean_cache(max_cache_age) {
+            warn!("could not clean the cache: {}", err);
+        }
 
-/**
-Generates a default Cargo manifest for the given input.
-*/
-fn default_manifest(input: &Input) -> Result<toml::Table> {
-    let mani_str = consts::DEFAULT_MANIFEST.replace("%n", input.safe_name());
-    toml::Parser::new(&mani_str).parse()
-        .ok_or("could not parse default manifest, somehow".into())
-}
+        try!(cache_action_for(&input, input_meta, args.flag_explain_cache_miss, args.flag_verify_exe))
+    };
+    info!("action: {:?}", action);
+    info!("pkg_path: {:?}", pkg_path);
+    info!("meta: {:?}", meta);
 
-/**
-Generates a partial Cargo manifest containing the specified dependencies.
-*/
-fn deps_manifest(deps: &[(String, String)]) -> Result<toml::Table> {
-    let mut mani_str = String::new();
-    mani_str.push_str("[dependencies]\n");
+    // This has to outlive everything else that uses the package, including running the script.
+    let _temp_pkg = match temp_pkg {
+        true => {
+            let pkg_path = pkg_path.clone();
+            Some(util::Defer::<_, MainError>::defer(move || {
+                info!("removing {:?}", pkg_path);
+                fs::remove_dir_all(&pkg_path).map_err(Into::into)
+            }))
+        },
+        false => None
+    };
+
+    // Cargo extensions get the package as-is; we have no idea what they'll do with it.
+    if let Some(ref subcommand) = args.flag_cargo_subcommand {
+        let mani_path = try!(generate_package(&input, &meta, &pkg_path, &settings));
+        return run_cargo_subcommand(subcommand, &mani_path, &meta, &args.arg_args);
+    }
+
+    if args.flag_check {
+        try!(generate_package(&input, &meta, &pkg_path, &settings));
+        let (code, check_time) = util::time(|| check_package(&input, &pkg_path, &meta, &settings));
+        if args.flag_timings {
+            let _ = writeln!(std::io::stderr(), "checked in {}", format_duration(check_time));
+        }
+        return code;
+    }
 
-    for &(ref name, ref ver) in deps {
-        mani_str.push_str(name);
-        mani_str.push_str("=");
+    // Compile if we need it.  There's nothing to lock or re-check in a temporary folder.  Either way, we end up with metadata which says where the executable is.
+    let (force, verify_exe) = (args.flag_force, args.flag_verify_exe);
+    let (meta, compile_time) = util::time(|| if temp_pkg {
+        info!("compiling...");
+        compile(&input, &meta, &pkg_path, &settings)
+    } else {
+        ensure_compiled(&input, &action, &pkg_path, meta, force, verify_exe, &settings)
+    });
+    let meta = try!(meta);
+
+    // However we leave from here on, `--timings` wants to know whether we built anything.
+    let compile_time = match action == CacheAction::Compile || args.flag_force {
+        true => Some(compile_time),
+        false => None
+    };
+    let timings = args.flag_timings;
+    let report_timings = |run_time| if timings {
+        let _ = writeln!(std::io::stderr(), "{}", format_timings(compile_time, run_time));
+    };
+
+    if args.flag_warm {
+        let status = match action == CacheAction::Compile || args.flag_force {
+            true => "warmed",
+            false => "already cached"
+        };
+        println!("{}: {}", meta.path.as_ref().map(|s| &**s).unwrap_or("<script>"), status);
+        report_timings(None);
+        return Ok(0);
+    }
+
+    if args.flag_tree {
+        return print_dep_tree(&pkg_path, &meta, &settings);
+    }
+
+    // There's nothing to run in a library, so just say where it ended up.
+    if let Some(ref crate_type) = meta.crate_type {
+        println!("{}", get_lib_path(&input, &pkg_path, &meta, crate_type).display());
+        report_timings(None);
+        return Ok(0);
+    }
+
+    if args.flag_print_exe_path {
+        let exe_path = try!(get_built_exe_path(&input, &pkg_path, &meta));
+        println!("{}", exe_path.display());
+        return Ok(0);
+    }
+
+    if args.flag_build_only {
+        if let Some(ref art_path) = args.flag_artifact_manifest {
+            let exe_path = try!(get_exe_path(&input, &pkg_path, &meta));
+            let art = ArtifactManifest {
+                path: meta.path.clone(),
+                target: meta.target.clone(),
+                profile: meta.profile().into(),
+                exe_path: exe_path.to_string_lossy().into_owned(),
+            };
+            info!("writing artifact manifest {:?}: {:?}", art_path, art);
+            try!(write_artifact_manifest(art_path, &art));
+        }
+        report_timings(None);
+        return Ok(0);
+    }
 
-        // We only want to quote the version if it *isn't* a table.
-        let quotes = match ver.starts_with("{") { true => "", false => "\"" };
-        mani_str.push_str(quotes);
-        mani_str.push_str(ver);
-        mani_str.push_str(quotes);
-        mani_str.push_str("\n");
+    // The post-run hook has to happen however we leave, so it gets deferred.
+    let exit_code = Cell::new(None);
+    let _post_run = args.flag_post_run.as_ref().map(|hook| {
+        let exit_code = &exit_code;
+        util::Defer::<_, MainError>::defer(move || run_post_run_hook(hook, exit_code.get()))
+    });
+
+    if args.flag_run_all {
+        let keep_going = args.flag_keep_going;
+        let (code, run_time) = util::time(|| run_all_bins(&pkg_path, &meta, &run_env, depth, keep_going));
+        let code = try!(code);
+        report_timings(Some(run_time));
+        exit_code.set(Some(code));
+        return Ok(code);
     }
 
-    toml::Parser::new(&mani_str).parse()
-        .ok_or("could not parse dependency manifest".into())
+    // If the tests fail, that's the end of the run, so it's reported just like the script failing.
+    if args.flag_run_tests_then_main {
+        let (code, test_time) = util::time(|| run_tests(&pkg_path, &meta, &settings));
+        let code = try!(code);
+        if code != 0 {
+            report_timings(Some(test_time));
+            exit_code.set(Some(code));
+            return Ok(code);
+        }
+    }
+
+    // Run it!
+    let exe_path = try!(get_built_exe_path(&input, &pkg_path, &meta));
+
+    info!("executing {:?}", exe_path);
+    let mut cmd = Command::new(exe_path);
+    cmd.args(&run_args);
+    for (k, v) in try!(manifest_env(&try!(get_pkg_manifest(&pkg_path)))) {
+        cmd.env(k, v);
+    }
+    for &(ref k, ref v) in &run_env {
+        cmd.env(k, v);
+    }
+    cmd.env(consts::DEPTH_ENV, (depth + 1).to_string());
+
+    let expr_out = args.flag_expr_out.as_ref().map(|s| &**s);
+    let (code, run_time) = util::time(|| run_script(cmd, run_stdin, expr_out));
+    let code = try!(code);
+
+    report_timings(Some(run_time));
+
+    exit_code.set(Some(code));
+    Ok(code)
 }
 
 /**
-Given two Cargo manifests, merges the second *into* the first.
-
-Note that the "merge" in this case is relatively simple: only *top-level* tables are actually merged; everything else is just outright replaced.
+Runs the script's executable, feeding it `run_stdin` if given, and writing its output to `expr_out` if given.  Returns its exit code.
 */
-fn merge_manifest(mut into_t: toml::Table, from_t: toml::Table) -> Result<toml::Table> {
-    for (k, v) in from_t {
-        match v {
-            toml::Value::Table(from_t) => {
-                use std::collections::btree_map::Entry::*;
+fn run_script(mut cmd: Command, run_stdin: Option<fs::File>, expr_out: Option<&str>) -> Result<i32> {
+    let out_f = match expr_out {
+        Some(out_path) => {
+            info!("writing output to {:?}", out_path);
+            cmd.stdout(Stdio::piped());
+            Some(try!(fs::File::create(out_path)))
+        },
+        None => None
+    };
 
-                // Merge.
-                match into_t.entry(k) {
-                    Vacant(e) => {
-                        e.insert(toml::Value::Table(from_t));
-                    },
-                    Occupied(e) => {
-                        let into_t = try!(as_table_mut(e.into_mut())
-                            .ok_or((Blame::Human, "cannot merge manifests: cannot merge \
-                                table and non-table values")));
-                        into_t.extend(from_t);
-                    }
-                }
-            },
-            v => {
-                // Just replace.
-                into_t.insert(k, v);
-            },
+    if run_stdin.is_some() {
+        cmd.stdin(Stdio::piped());
+    }
+
+    let mut child = try!(cmd.spawn());
+
+    // The file gets fed in from another thread, so that the script can't get stuck waiting for us to read its output.
+    let stdin_feeder = run_stdin.map(|mut in_f| {
+        let mut child_in = child.stdin.take().expect("child stdin was not captured");
+        std::thread::spawn(move || std::io::copy(&mut in_f, &mut child_in).map(|_| ()))
+    });
+
+    if let Some(mut out_f) = out_f {
+        {
+            let mut child_out = child.stdout.take().expect("child stdout was not captured");
+            try!(std::io::copy(&mut child_out, &mut out_f));
         }
+        try!(out_f.flush());
     }
 
-    return Ok(into_t);
+    let code = try!(child.wait()
+        .map(|st| st.code().unwrap_or(1)));
 
-    fn as_table_mut(t: &mut toml::Value) -> Option<&mut toml::Table> {
-        match *t {
-            toml::Value::Table(ref mut t) => Some(t),
-            _ => None
+    // If the script stopped reading before the end of the file, that's its business.
+    if let Some(stdin_feeder) = stdin_feeder {
+        match stdin_feeder.join() {
+            Ok(Ok(())) => (),
+            Ok(Err(err)) => info!("could not feed all of --stdin-file to the script: {}", err),
+            Err(_) => info!("feeding --stdin-file to the script panicked"),
         }
     }
+
+    Ok(code)
 }
 
 /**
-This represents what to do with the input provided by the user.
+Fails if an option which only makes sense for some kinds of input was `given` with some other kind.  `allowed` says whether the input is one of the right kinds.
 */
-#[derive(Clone, Debug, Eq, PartialEq)]
-enum CacheAction {
-    /// Compile the input into a fresh executable.
-    Compile,
-    /// Don't bother compiling; just execute what's in the cache.
-    Execute,
+fn only_with(given: bool, allowed: bool, msg: &'static str) -> Result<()> {
+    match given && !allowed {
+        true => Err((Blame::Human, msg).into()),
+        false => Ok(())
+    }
 }
 
 /**
-The metadata here serves two purposes:
+Reads the whole of a file the user gave us.  If that fails, it's down to them.
+*/
+fn read_user_file<P>(path: P) -> Result<String>
+where P: AsRef<Path> {
+    let path = path.as_ref();
+    let mut s = String::new();
+    try!(fs::File::open(path)
+        .and_then(|mut f| f.read_to_string(&mut s))
+        .map_err(|err| (Blame::Human, format!("could not read {}: {}", path.display(), err))));
+    Ok(s)
+}
 
-1. It records everything necessary for compilation and execution of a package.
-2. It records everything that must be exactly the same in order for a cached executable to still be valid, in addition to the content hash.
+/**
+Works out the name and absolute path of a script that's been found at `path`.
+
+This resolves `.`, `..` and symlinks, so that every way of getting to the same script ends up with the same package.  In particular, a script run through a symlink shares a package (name and all) with the script the symlink points to.  If that fails, the absolute path is still good enough to run it.
 */
-#[derive(Clone, Debug, Eq, PartialEq, RustcDecodable, RustcEncodable)]
-struct PackageMetadata {
-    /// Path to the script file.
-    path: Option<String>,
+fn resolve_script(path: &Path) -> Result<(String, PathBuf)> {
+    let abs_path = try!(std::env::current_dir()).join(path);
+    let script_path = fs::canonicalize(&abs_path).unwrap_or(abs_path);
+    let script_name = script_path.file_stem()
+        .map(|os| os.to_string_lossy().into_owned())
+        .unwrap_or("unknown".into());
+    Ok((script_name, script_path))
+}
 
-    /// Last-modified timestamp for script file.
-    modified: Option<u64>,
+/**
+Works out whether to build in debug mode, given `--debug`, `--release`, and the profile set in the environment (if any).
 
-    /// Was the script compiled in debug mode?
-    debug: bool,
+Explicit flags win over the environment, which wins over the default of building in release mode.
+*/
+fn resolve_profile(debug: bool, release: bool, env_profile: Option<&str>) -> Result<bool> {
+    match (debug, release) {
+        (true, true) => Err((Blame::Human, "cannot specify both --debug and --release").into()),
+        (true, false) => Ok(true),
+        (false, true) => Ok(false),
+        (false, false) => match env_profile {
+            Some("debug") => Ok(true),
+            Some("release") | Some("") | None => Ok(false),
+            Some(profile) => Err((Blame::Human,
+                format!("{} must be `debug` or `release`, not '{}'", consts::PROFILE_ENV, profile)).into())
+        }
+    }
+}
 
-    /// Sorted list of dependencies.
-    deps: Vec<(String, String)>,
+/**
+Drops the trailing newlines from an expression read from a file or stdin.  They're almost always there, and almost never in an expression given directly.
+*/
+fn trim_expr(mut expr: String) -> String {
+    let len = expr.trim_right_matches(|c: char| c == '\n' || c == '\r').len();
+    expr.truncate(len);
+    expr
 }
 
 /**
-For the given input, this constructs the package metadata and checks the cache to see what should be done.
+Checks the flags given with `--rustc-flag`.  An empty one would otherwise turn into a baffling error from `rustc`, long after we could have said what was wrong.
 */
-fn cache_action_for(input: &Input, debug: bool, deps: Vec<(String, String)>) -> (CacheAction, PathBuf, PackageMetadata) {
-    use std::fs::PathExt;
+fn check_rustc_flags(flags: &[String]) -> Result<()> {
+    if flags.iter().any(|f| f.trim() == "") {
+        try!(Err((Blame::Human, "--rustc-flag cannot be empty")));
+    }
+    Ok(())
+}
 
-    // This can't fail.  Seriously, we're *fucked* if we can't work this out.
-    let cache_path = get_cache_path().unwrap();
-    info!("cache_path: {:?}", cache_path);
+/**
+Formats the line `--timings` prints.  `compile_time` is `None` if the script was already cached, and `run_time` is `None` if it wasn't run.
+*/
+fn format_timings(compile_time: Option<Duration>, run_time: Option<Duration>) -> String {
+    let built = match compile_time {
+        Some(compile_time) => format!("compiled in {}", format_duration(compile_time)),
+        None => "cached".into()
+    };
+    match run_time {
+        Some(run_time) => format!("{}, ran in {}", built, format_duration(run_time)),
+        None => built
+    }
+}
+
+/**
+Formats a duration for `--timings`, in seconds to one decimal place.
+*/
+fn format_duration(d: Duration) -> String {
+    format!("{}.{}s", d.as_secs(), d.subsec_nanos() / 100_000_000)
+}
+
+/**
+Returns how many levels deep this invocation is in scripts running `cargo script`.
+*/
+fn get_recursion_depth() -> u32 {
+    parse_recursion_depth(std::env::var(consts::DEPTH_ENV).ok().as_ref().map(|d| &**d))
+}
 
-    let id = {
-        let deps_iter = deps.iter()
-            .map(|&(ref n, ref v)| (n as &str, v as &str));
+/**
+Parses the depth recorded in the environment, if any.  Anything we can't make sense of counts as not being nested at all.
+*/
+fn parse_recursion_depth(depth: Option<&str>) -> u32 {
+    depth.and_then(|depth| depth.parse().ok())
+        .unwrap_or(0)
+}
+
+/**
+Refuses to go any further once `depth` reaches `limit` (from `--recursion-guard`).
+*/
+fn check_recursion_depth(depth: u32, limit: u32) -> Result<()> {
+    if depth >= limit {
+        try!(Err((Blame::Human,
+            format!("cargo-script has been invoked {} levels deep; suspected runaway recursion \
+                (use --recursion-guard to raise the limit)", depth))));
+    }
+    Ok(())
+}
 
-        // Again, also fucked if we can't work this out.
-        input.compute_id(deps_iter).unwrap()
+/**
+Runs the `--post-run` hook using the system shell.  `exit_code` is the script's exit code, if it got far enough to have one.
+*/
+fn run_post_run_hook(hook: &str, exit_code: Option<i32>) -> Result<()> {
+    info!("running post-run hook {:?} (exit code {:?})", hook, exit_code);
+
+    let mut cmd = if cfg!(windows) {
+        let mut cmd = Command::new("cmd");
+        cmd.arg("/C");
+        cmd
+    } else {
+        let mut cmd = Command::new("sh");
+        cmd.arg("-c");
+        cmd
     };
-    info!("id: {:?}", id);
+    cmd.arg(hook)
+        .env(consts::EXIT_CODE_ENV, exit_code.map(|c| c.to_string()).unwrap_or(String::new()));
+
+    let st = try!(cmd.status());
+    match st.code() {
+        Some(0) => Ok(()),
+        Some(st) => Err(format!("post-run hook failed with status {}", st).into()),
+        None => Err("post-run hook failed".into())
+    }
+}
 
-    let pkg_path = cache_path.join(&id);
-    info!("pkg_path: {:?}", pkg_path);
+/**
+Runs every binary declared in the manifest of the package in the given cache folder, in order.
 
-    // Construct input metadata.
-    let input_meta = {
-        let (path, mtime) = match *input {
-            Input::File(_, path, _, mtime)
-                => (Some(path.to_string_lossy().into_owned()), Some(mtime)),
-            Input::Expr(..)
-            | Input::Loop(..)
-                => (None, None)
-        };
-        PackageMetadata {
-            path: path,
-            modified: mtime,
-            debug: debug,
-            deps: deps,
+Each one gets the manifest's environment, then `run_env` on top of that.  Returns zero if they all succeeded, otherwise the exit code of the first one that failed.
+*/
+fn run_all_bins<P>(pkg_path: P, meta: &PackageMetadata, run_env: &[(String, String)], depth: u32, keep_going: bool) -> Result<i32>
+where P: AsRef<Path> {
+    let pkg_path = pkg_path.as_ref();
+    let mani = try!(get_pkg_manifest(pkg_path));
+    let bins = manifest_bin_names(&mani);
+    let env = try!(manifest_env(&mani));
+    info!("bins: {:?}", bins);
+
+    let mut result = 0;
+    for bin in bins {
+        let exe_path = get_bin_path(pkg_path, meta, &bin);
+        info!("executing {:?}", exe_path);
+        let mut cmd = Command::new(exe_path);
+        for &(ref k, ref v) in env.iter().chain(run_env) {
+            cmd.env(k, v);
+        }
+        let code = try!(cmd
+            .env(consts::DEPTH_ENV, (depth + 1).to_string())
+            .status()
+            .map(|st| st.code().unwrap_or(1)));
+
+        if code != 0 {
+            let _ = writeln!(std::io::stderr(), "{} failed with status {}", bin, code);
+            if result == 0 {
+                result = code;
+            }
+            if !keep_going {
+                break;
+            }
         }
+    }
+
+    Ok(result)
+}
+
+/**
+Shows the dependency tree of the package in the given cache folder using `cargo tree`.
+*/
+fn print_dep_tree<P>(pkg_path: P, meta: &PackageMetadata, settings: &BuildSettings) -> Result<i32>
+where P: AsRef<Path> {
+    let mut cmd = dep_tree_command(pkg_path.as_ref(), meta, settings);
+    info!("running {:?}", cmd);
+    Ok(try!(cmd.status()
+        .map(|st| st.code().unwrap_or(1))))
+}
+
+/**
+Works out the `cargo tree` command for the package in the given cache folder.  Only the flags which make sense for `cargo tree` are passed along.
+*/
+fn dep_tree_command(pkg_path: &Path, meta: &PackageMetadata, settings: &BuildSettings) -> Command {
+    let mani_path = pkg_path.join("Cargo.toml");
+    let mut cmd = toolchain_command("cargo", meta.toolchain());
+    cmd.arg("tree")
+        .arg("--manifest-path")
+        .arg(&*mani_path.to_string_lossy());
+
+    if let Some(ref target) = meta.target {
+        cmd.arg("--target").arg(target);
+    }
+
+    cmd.arg("--color").arg(settings.color.as_cargo_arg());
+
+    if settings.offline {
+        cmd.arg("--offline");
+    }
+    cmd
+}
+
+/**
+Reads the `[dependencies]` from an existing Cargo manifest, turning them into `--dep` style `name=version` specs.
+
+Detailed dependencies become inline tables.  Since the package gets built from somewhere else entirely, any relative `path`s are made absolute.
+*/
+fn read_deps_from(mani_path: &Path) -> Result<Vec<String>> {
+    let mani_str = try!(read_user_file(mani_path));
+    let mani = try!(parse_user_manifest("--dep-from", &mani_str));
+
+    let deps = match mani.get("dependencies").and_then(|d| d.as_table()) {
+        Some(deps) => deps,
+        None => return Ok(vec![])
     };
-    info!("input_meta: {:?}", input_meta);
 
-    // Lazy powers, ACTIVATE!
-    macro_rules! bail {
-        () => {
-            return (CacheAction::Compile, pkg_path, input_meta)
+    let base_path = try!(std::env::current_dir()).join(mani_path);
+    let base_path = base_path.parent().unwrap_or(&base_path);
+
+    let mut specs = vec![];
+    for (name, dep) in deps {
+        let spec = match *dep {
+            toml::Value::String(ref version) => version.clone(),
+            toml::Value::Table(ref table) => {
+                let fields: Vec<String> = table.iter()
+                    .map(|(k, v)| match (&**k, v) {
+                        ("path", &toml::Value::String(ref path)) => format!("{} = {}", k,
+                            toml::Value::String(base_path.join(path).to_string_lossy().into_owned())),
+                        _ => format!("{} = {}", k, v)
+                    })
+                    .collect();
+                format!("{{ {} }}", fields.join(", "))
+            },
+            _ => try!(Err((Blame::Human,
+                format!("dependency '{}' in {:?} is neither a version nor a table", name, mani_path))))
+        };
+        specs.push(format!("{}={}", name, spec));
+    }
+    Ok(specs)
+}
+
+/**
+Turns a `git:URL` or `path:PATH` dependency source into the inline table `deps_manifest` expects.  Anything else is assumed to be a version, and is left alone.
+
+Relative paths are made absolute, since the package gets built from somewhere else entirely.
+*/
+fn expand_dep_source(name: &str, version: &str) -> Result<String> {
+    let (key, source) = if version.starts_with("git:") {
+        ("git", &version[4..])
+    } else if version.starts_with("path:") {
+        ("path", &version[5..])
+    } else {
+        return Ok(version.into());
+    };
+
+    if source == "" {
+        try!(Err((Blame::Human,
+            format!("dependency '{}' has an empty {} source", name, key))));
+    }
+
+    let value = match key {
+        "path" => try!(std::env::current_dir()).join(source).to_string_lossy().into_owned(),
+        _ => source.into()
+    };
+
+    Ok(format!("{{ {} = {} }}", key, toml::Value::String(value)))
+}
+
+/**
+Folds `--dep-feature` specs (`name/feature`) into the specs of the dependencies they belong to.  Every one of them has to name a dependency that's already in `deps`.
+*/
+fn apply_dep_features(deps: &mut std::collections::HashMap<String, String>, specs: &[String]) -> Result<()> {
+    use std::collections::HashMap;
+
+    let mut dep_features: HashMap<&str, Vec<String>> = HashMap::new();
+    for spec in specs {
+        let mut parts = spec.splitn(2, '/');
+        let (name, feature) = match (parts.next(), parts.next()) {
+            (Some(name), Some(feature)) if name != "" && feature != "" => (name, feature),
+            _ => try!(Err((Blame::Human,
+                format!("dependency feature '{}' should be given as `name/feature`", spec))))
+        };
+
+        if !deps.contains_key(name) {
+            try!(Err((Blame::Human,
+                format!("cannot enable feature '{}' of '{}', since it isn't a dependency given with --dep",
+                    feature, name))));
         }
+
+        dep_features.entry(name).or_insert(vec![]).push(feature.into());
     }
 
-    let cache_meta = match get_pkg_metadata(&pkg_path) {
-        Ok(meta) => meta,
-        Err(err) => {
-            info!("recompiling because: failed to load metadata");
-            debug!("get_pkg_metadata error: {}", err.description());
-            bail!()
+    for (name, features) in dep_features {
+        let version = deps.get_mut(name).expect("dependency with features went missing");
+        *version = try!(with_dep_features(name, version, &features));
+    }
+    Ok(())
+}
+
+/**
+Adds a list of features to the spec of dependency `name`, turning it into an inline table if it isn't one already.  If the spec already lists some features, the new ones are added to them.
+*/
+fn with_dep_features(name: &str, version: &str, features: &[String]) -> Result<String> {
+    let mut table = if version.starts_with("{") {
+        let spec = format!("spec = {}", version);
+        let parsed = toml::Parser::new(&spec).parse();
+        match parsed.and_then(|mut t| t.remove("spec")) {
+            Some(toml::Value::Table(table)) => table,
+            _ => try!(Err((Blame::Human,
+                format!("could not parse the spec for dependency '{}': {}", name, version))))
         }
+    } else {
+        let mut table = toml::Table::new();
+        table.insert("version".into(), toml::Value::String(version.into()));
+        table
+    };
+
+    let mut all_features = match table.remove("features") {
+        None => vec![],
+        Some(toml::Value::Array(existing)) => {
+            let mut all_features = vec![];
+            for feature in existing {
+                match feature {
+                    toml::Value::String(feature) => all_features.push(feature),
+                    _ => try!(Err((Blame::Human,
+                        format!("the features of dependency '{}' must be strings", name))))
+                }
+            }
+            all_features
+        },
+        Some(_) => try!(Err((Blame::Human,
+            format!("the features of dependency '{}' must be a list", name))))
     };
+    all_features.extend(features.iter().cloned());
+    all_features.sort();
+    all_features.dedup();
+    table.insert("features".into(),
+        toml::Value::Array(all_features.into_iter().map(toml::Value::String).collect()));
+
+    let fields: Vec<String> = table.iter()
+        .map(|(k, v)| format!("{} = {}", k, v))
+        .collect();
+    Ok(format!("{{ {} }}", fields.join(", ")))
+}
+
+/**
+Appends a version to a dependency spec which doesn't have one: the one pinned in the environment (see `pinned_dep_version`), or `*`.
+*/
+fn with_dep_version(dep: String) -> String {
+    match dep.find('=') {
+        Some(_) => dep,
+        None => {
+            let version = pinned_dep_version(&dep).unwrap_or("*".into());
+            format!("{}={}", dep, version)
+        }
+    }
+}
+
+/**
+Looks up the version of a dependency pinned in the environment, if there is one.
+
+The pin for package `name` lives in `CARGO_SCRIPT_DEP_name`, with any hyphens in the package name replaced by underscores.
+*/
+fn pinned_dep_version(name: &str) -> Option<String> {
+    let var = format!("{}{}", consts::DEP_PIN_ENV_PREFIX, name.replace("-", "_"));
+    match std::env::var(&var) {
+        Ok(ref version) if version.trim() != "" => {
+            info!("using {}={:?}", var, version);
+            Some(version.trim().into())
+        },
+        _ => None
+    }
+}
+
+/**
+Builds and runs the tests for the package in the given cache folder, returning Cargo's exit code.
+
+This leaves finding and running the test harness to Cargo, since it lives somewhere else entirely.
+*/
+fn run_tests<P>(pkg_path: P, meta: &PackageMetadata, settings: &BuildSettings) -> Result<i32>
+where P: AsRef<Path> {
+    let mut cmd = cargo_command("test", pkg_path.as_ref(), meta, settings);
+    info!("running {:?}", cmd);
+    Ok(try!(cmd.status()
+        .map(|st| st.code().unwrap_or(1))))
+}
 
-    if cache_meta != input_meta {
-        info!("recompiling because: metadata did not match");
-        debug!("input metadata: {:?}", input_meta);
-        debug!("cache metadata: {:?}", cache_meta);
-        bail!()
+/**
+Type-checks the package in the given cache folder with `cargo check`.  As with building it, a failure is blamed on the script.
+*/
+fn check_package(input: &Input, pkg_path: &Path, meta: &PackageMetadata, settings: &BuildSettings) -> Result<i32> {
+    let mut cmd = cargo_command("check", pkg_path, meta, settings);
+    info!("running {:?}", cmd);
+    let status = try!(cmd.status()
+        .map_err(|err| (Blame::Human, format!("could not run cargo: {}", err))));
+    match status.code() {
+        Some(0) => Ok(0),
+        Some(code) => Err(MainError::Compile(input.safe_name(), code)),
+        None => try!(Err((Blame::Human,
+            format!("cargo was stopped before it finished checking `{}`", input.safe_name()))))
     }
+}
+
+/**
+Works out where the package's executable is, and makes sure it's actually there.
+
+If Cargo put the executable somewhere else, running it would just give a baffling "file not found".  That's our fault, not the user's.
+*/
+fn get_built_exe_path(input: &Input, pkg_path: &Path, meta: &PackageMetadata) -> Result<PathBuf> {
+    use std::fs::PathExt;
 
-    // Next test: does the executable exist at all?
-    let exe_path = get_exe_path(input, &pkg_path, &input_meta);
+    let exe_path = try!(get_exe_path(input, pkg_path, meta));
     if !exe_path.is_file() {
-        info!("recompiling because: executable doesn't exist or isn't a file");
-        bail!()
+        try!(Err(format!("could not find the {} executable at {:?} after building",
+            meta.profile(), exe_path)));
     }
+    Ok(exe_path)
+}
 
-    // That's enough; let's just go with it.
-    (CacheAction::Execute, pkg_path, input_meta)
+/**
+Runs a Cargo subcommand on the package with the given manifest, passing it `args`.
+*/
+fn run_cargo_subcommand(subcommand: &str, mani_path: &Path, meta: &PackageMetadata, args: &[String]) -> Result<i32> {
+    let mut cmd = toolchain_command("cargo", meta.toolchain());
+    cmd.arg(subcommand)
+        .arg("--manifest-path")
+        .arg(&*mani_path.to_string_lossy())
+        .args(args);
+    info!("running {:?}", cmd);
+    Ok(try!(cmd.status()
+        .map(|st| st.code().unwrap_or(1))))
 }
 
 /**
-Figures out where the output executable for the input should be.
+Describes a built executable well enough that it can be run later without having to work anything out again.  This is written by `--artifact-manifest`, and read by `--run-artifact`.
+*/
+#[derive(Clone, Debug, RustcDecodable, RustcEncodable)]
+struct ArtifactManifest {
+    /// Path to the script file, if there was one.
+    path: Option<String>,
+
+    /// Target triple the executable was built for, if not the default.
+    target: Option<String>,
+
+    /// Profile the executable was built with.
+    profile: String,
 
-Note that this depends on Cargo *not* suddenly changing its mind about where stuff lives.  In theory, I should be able to just *ask* Cargo for this information, but damned if I can't find an easy way to do it...
+    /// Path to the executable itself.
+    exe_path: String,
+}
+
+/**
+Save an artifact manifest to the given path.
 */
-fn get_exe_path<P>(input: &Input, pkg_path: P, meta: &PackageMetadata) -> PathBuf
+fn write_artifact_manifest<P>(art_path: P, art: &ArtifactManifest) -> Result<()>
 where P: AsRef<Path> {
-    let profile = match meta.debug {
-        true => "debug",
-        false => "release"
-    };
-    let mut exe_path = pkg_path.as_ref().join("target").join(profile).join(&input.safe_name()).into_os_string();
-    exe_path.push(std::env::consts::EXE_SUFFIX);
-    exe_path.into()
+    let mut art_file = try!(fs::File::create(art_path));
+    let art_str = try!(rustc_serialize::json::encode(art)
+        .map_err(|err| err.to_string()));
+    try!(write!(&mut art_file, "{}", art_str));
+    try!(art_file.flush());
+    Ok(())
 }
 
 /**
-Load the package metadata, given the path to the package's cache folder.
+Runs the executable described by the artifact manifest at the given path, returning its exit code.
 */
-fn get_pkg_metadata<P>(pkg_path: P) -> Result<PackageMetadata>
+fn run_artifact<P>(art_path: P, args: &[String]) -> Result<i32>
 where P: AsRef<Path> {
-    let meta_path = pkg_path.as_ref().join(consts::METADATA_FILE);
-    debug!("meta_path: {:?}", meta_path);
-    let mut meta_file = try!(fs::File::open(&meta_path));
+    use std::fs::PathExt;
 
-    let meta_str = {
-        let mut s = String::new();
-        meta_file.read_to_string(&mut s).unwrap();
-        s
-    };
-    let meta: PackageMetadata = try!(rustc_serialize::json::decode(&meta_str)
-        .map_err(|err| err.to_string()));
+    let art_path = art_path.as_ref();
+
+    let art_str = try!(read_user_file(art_path));
+    let art: ArtifactManifest = try!(rustc_serialize::json::decode(&art_str)
+        .map_err(|err| (Blame::Human, format!("could not read artifact manifest {}: {}",
+            art_path.display(), err))));
+    info!("art: {:?}", art);
 
-    Ok(meta)
+    // The cache may well have been cleared out from under us since the manifest was written.
+    if !Path::new(&art.exe_path).is_file() {
+        try!(Err((Blame::Human,
+            format!("executable {} from artifact manifest {} no longer exists; rebuild the script",
+                art.exe_path, art_path.display()))));
+    }
+
+    info!("executing {:?}", art.exe_path);
+    Ok(try!(Command::new(&art.exe_path).args(args)
+        .env(consts::DEPTH_ENV, (get_recursion_depth() + 1).to_string())
+        .status()
+        .map(|st| st.code().unwrap_or(1))))
 }
 
 /**
-Save the package metadata, given the path to the package's cache folder.
+Prints a summary of the cache: how many packages there are of each kind, how much space they take up, which are the oldest and newest, and how many are old enough to be cleaned up.
+
+Packages whose metadata can't be read are counted separately, and left out of the oldest/newest comparison.
 */
-fn write_pkg_metadata<P>(pkg_path: P, meta: &PackageMetadata) -> Result<()>
-where P: AsRef<Path> {
-    let meta_path = pkg_path.as_ref().join(consts::METADATA_FILE);
-    debug!("meta_path: {:?}", meta_path);
-    let mut meta_file = try!(fs::File::create(&meta_path));
-    let meta_str = try!(rustc_serialize::json::encode(meta)
-        .map_err(|err| err.to_string()));
-    try!(write!(&mut meta_file, "{}", meta_str));
-    try!(meta_file.flush());
+fn print_cache_stats() -> Result<i32> {
+    let cache_path = try!(get_cache_path());
+    info!("cache_path: {:?}", cache_path);
+
+    let cutoff = cache_cutoff(try!(current_time(&cache_path)), consts::MAX_CACHE_AGE_MS);
+    let stdout = std::io::stdout();
+    try!(write_cache_stats(&mut stdout.lock(), &cache_path, cutoff));
+    Ok(0)
+}
+
+/**
+Does the work for `print_cache_stats`.  Packages last built before `cutoff` are counted as stale, just as `clean_cache` would see them.
+*/
+fn write_cache_stats<W>(out: &mut W, cache_path: &Path, cutoff: u64) -> Result<()>
+where W: Write {
+    let mut total = 0;
+    let (mut files, mut stdins, mut exprs, mut loops) = (0, 0, 0, 0);
+    let mut unreadable = 0;
+    let mut stale = 0;
+    let mut size = 0;
+    let mut oldest: Option<(u64, String)> = None;
+    let mut newest: Option<(u64, String)> = None;
+
+    for (id, path) in try!(get_cache_packages(&cache_path)) {
+        total += 1;
+        size += try!(util::dir_size(&path));
+
+        if id.starts_with("file-") {
+            files += 1;
+        } else if id.starts_with("stdin-") {
+            stdins += 1;
+        } else if id.starts_with("expr-") {
+            exprs += 1;
+        } else if id.starts_with("loop-") {
+            loops += 1;
+        }
+
+        // As with cleaning, a package without any metadata at all isn't stale, just mysterious.
+        let mtime = fs::metadata(path.join(consts::METADATA_FILE))
+            .map(|md| md.modified()).ok();
+        if mtime.map(|mtime| mtime < cutoff).unwrap_or(false) {
+            stale += 1;
+        }
+
+        if get_pkg_metadata(&path).is_err() {
+            unreadable += 1;
+            continue;
+        }
+        let mtime = mtime.unwrap_or(0);
+
+        if oldest.as_ref().map(|&(t, _)| mtime < t).unwrap_or(true) {
+            oldest = Some((mtime, id.clone()));
+        }
+        if newest.as_ref().map(|&(t, _)| mtime > t).unwrap_or(true) {
+            newest = Some((mtime, id));
+        }
+    }
+
+    try!(writeln!(out, "cache:      {}", cache_path.display()));
+    try!(writeln!(out, "packages:   {} ({} file, {} stdin, {} expr, {} loop)",
+        total, files, stdins, exprs, loops));
+    try!(writeln!(out, "unreadable: {}", unreadable));
+    try!(writeln!(out, "stale:      {}", stale));
+    try!(writeln!(out, "size:       {} bytes", size));
+    if let Some((_, id)) = oldest {
+        try!(writeln!(out, "oldest:     {}", id));
+    }
+    if let Some((_, id)) = newest {
+        try!(writeln!(out, "newest:     {}", id));
+    }
+
     Ok(())
 }
 
 /**
-Returns the path to the cache directory.
+Prints a table of the packages in the cache.  Packages whose metadata can't be read are listed as corrupt.
 */
-fn get_cache_path() -> Result<PathBuf> {
-    let cache_path = try!(platform::get_cache_dir_for("Cargo"));
-    Ok(cache_path.join("script-cache"))
+fn print_cache_list() -> Result<i32> {
+    let cache_path = try!(get_cache_path());
+    info!("cache_path: {:?}", cache_path);
+
+    let stdout = std::io::stdout();
+    try!(write_cache_list(&mut stdout.lock(), &cache_path));
+    Ok(0)
 }
 
 /**
-Attempts to locate the script specified by the given path.  If the path as-given doesn't yield anything, it will try adding file extensions.
+Does the work for `print_cache_list`.
+*/
+fn write_cache_list<W>(out: &mut W, cache_path: &Path) -> Result<()>
+where W: Write {
+    try!(writeln!(out, "ID\tPROFILE\tDEPS\tSIZE\tSCRIPT"));
+    for (id, path) in try!(get_cache_packages(cache_path)) {
+        let size = try!(util::dir_size(&path));
+        match get_pkg_metadata(&path) {
+            Ok(meta) => try!(writeln!(out, "{}\t{}\t{}\t{}\t{}",
+                id, meta.profile(), meta.deps.len(), size,
+                meta.path.as_ref().map(|s| &**s).unwrap_or("-"))),
+            Err(err) => {
+                debug!("get_pkg_metadata error: {}", err);
+                try!(writeln!(out, "{}\tcorrupt\t-\t{}\t-", id, size));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/**
+Creates a fresh, empty folder outside the cache to build the input in, for `--no-cache`.  The caller is responsible for removing it.
+*/
+fn make_temp_pkg_dir(input: &Input) -> Result<PathBuf> {
+    let base = std::env::temp_dir();
+    let prefix = format!("cargo-script-{}", input.safe_name());
+
+    // Creating a directory fails if it already exists, so just keep counting until we find one nobody else has.
+    for n in 0.. {
+        let pkg_path = base.join(format!("{}-{}", prefix, n));
+        match fs::create_dir(&pkg_path) {
+            Ok(()) => {
+                info!("temp pkg_path: {:?}", pkg_path);
+                return Ok(pkg_path);
+            },
+            Err(ref err) if err.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err.into())
+        }
+    }
+    unreachable!()
+}
+
+/**
+Parses a duration given as a number and a unit, such as `30m` or `7d`, into milliseconds.  Zero doesn't need a unit, since it's zero either way.
+*/
+fn parse_duration(s: &str) -> Result<u64> {
+    const UNITS: &'static [(char, u64)] = &[
+        ('s', 1000),
+        ('m', 60 * 1000),
+        ('h', 60 * 60 * 1000),
+        ('d', 24 * 60 * 60 * 1000),
+        ('w', 7 * 24 * 60 * 60 * 1000),
+    ];
+
+    let invalid = || (Blame::Human, format!(
+        "invalid duration '{}'; expected a number followed by a unit, like `7d` or `12h`", s));
+
+    if s == "0" {
+        return Ok(0);
+    }
+
+    let unit = try!(s.chars().last().ok_or_else(&invalid));
+    let scale = try!(UNITS.iter().find(|&&(u, _)| u == unit).map(|&(_, scale)| scale)
+        .ok_or_else(&invalid));
+    let n: u64 = try!(s[..s.len() - unit.len_utf8()].parse().map_err(|_| invalid()));
+    n.checked_mul(scale).ok_or_else(invalid).map_err(Into::into)
+}
+
+/**
+Loads the named user template.  If there's no such template, this warns (unless `quiet`) and returns `None`, so that the built-in one gets used instead.
+*/
+fn load_template(name: &str, quiet: bool) -> Result<Option<String>> {
+    let templ_path = try!(get_config_path()).join("templates").join(name).with_extension("rs");
+    info!("templ_path: {:?}", templ_path);
+
+    let mut templ_f = match fs::File::open(&templ_path) {
+        Ok(f) => f,
+        Err(err) => {
+            if !quiet {
+                let _ = writeln!(std::io::stderr(),
+                    "warning: could not open template {:?} ({}); using the built-in template",
+                    templ_path, err);
+            }
+            return Ok(None);
+        }
+    };
+
+    let mut templ = String::new();
+    try!(templ_f.read_to_string(&mut templ));
+
+    if !templ.contains("%%") {
+        try!(Err((Blame::Human,
+            format!("template {:?} doesn't contain `%%`, so there's nowhere to put the input",
+                templ_path))));
+    }
+    Ok(Some(templ))
+}
+
+/**
+Works out which byte `--split` asked for.  This has to be a single character, or one of a few backslash escapes, since the shell makes some separators (like NUL) awkward to type.
+
+Only ASCII characters are allowed, since the loop splits its input on bytes.
+*/
+fn parse_loop_separator(sep: &str) -> Result<u8> {
+    let c = match sep {
+        "\\0" => '\0',
+        "\\t" => '\t',
+        "\\n" => '\n',
+        "\\\\" => '\\',
+        _ => {
+            let mut chars = sep.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => c,
+                _ => try!(Err((Blame::Human, format!(
+                    "--split must be a single character, not {:?}", sep))))
+            }
+        }
+    };
+
+    if (c as u32) > 0x7f {
+        try!(Err((Blame::Human, format!(
+            "--split must be an ASCII character, not {:?}", c))));
+    }
+
+    Ok(c as u8)
+}
+
+/**
+Attempts to locate the script specified by the given path.  If it can't be found relative to the current directory, and the path is relative, each of the directories in `CARGO_SCRIPT_PATH` is tried in turn.
 */
 fn find_script<P>(path: P) -> Option<(PathBuf, fs::File)>
 where P: AsRef<Path> {
     let path = path.as_ref();
 
+    if let Some(found) = find_script_at(path) {
+        return Some(found);
+    }
+
+    if path.is_absolute() {
+        return None;
+    }
+
+    let search_path = match std::env::var_os(consts::SCRIPT_PATH_ENV) {
+        Some(search_path) => search_path,
+        None => return None
+    };
+
+    for dir in std::env::split_paths(&search_path) {
+        info!("searching {:?}", dir);
+        if let Some(found) = find_script_at(dir.join(path)) {
+            return Some(found);
+        }
+    }
+
+    None
+}
+
+/**
+Attempts to locate the script at the given path.  If the path as-given doesn't yield anything, it will try adding file extensions.
+*/
+fn find_script_at<P>(path: P) -> Option<(PathBuf, fs::File)>
+where P: AsRef<Path> {
+    let path = path.as_ref();
+
     // Try the path directly.
     if let Ok(file) = fs::File::open(path) {
         return Some((path.into(), file));
     }
 
-    // If it had an extension, don't bother trying any others.
-    if path.extension().is_some() {
+    // If there's no file name (*e.g.* a drive or share root), there's nothing to put an extension on.
+    if path.file_name().is_none() {
+        return None;
+    }
+
+    // If it had an extension, don't bother trying any others.  Windows ignores trailing dots on file names, so `foo.` doesn't count as having one there.
+    let has_ext = match path.extension() {
+        Some(ext) => !(cfg!(windows) && ext.to_str() == Some("")),
+        None => false
+    };
+    if has_ext {
         return None;
     }
 
@@ -646,122 +2101,630 @@ where P: AsRef<Path> {
     None
 }
 
-/**
-Represents an input source for a script.
-*/
-#[derive(Clone, Debug)]
-enum Input<'a> {
-    /**
-    The input is a script file.
+#[cfg(test)]
+mod tests {
+    use super::{Args, USAGE};
+    use super::{apply_dep_features, build_settings, check_package, check_recursion_depth};
+    use super::{check_rustc_flags, dep_tree_command, expand_dep_source, find_project_config};
+    use super::{find_script, format_timings, get_argv, get_built_exe_path, merge_flags};
+    use super::{parse_duration, parse_loop_separator, parse_recursion_depth, pinned_dep_version};
+    use super::{resolve_config_dep, resolve_profile, resolve_script, run_all_bins, run_script};
+    use super::{trim_expr, watch_should_rerun, with_dep_features, with_dep_version};
+    use super::{write_cache_list, write_cache_stats};
+    use cargo_script::consts;
+    use cargo_script::{BuildSettings, Color, ExprStyle, Input, PackageMetadata};
+    use cargo_script::{cargo_command, generate_package, get_bin_path};
+    use cargo_script::error::MainError;
+    use docopt;
+    use std::collections::HashMap;
+    use std::fs;
+    use std::io::prelude::*;
+    use std::path::{Path, PathBuf};
+    use std::time::Duration;
+
+    fn argv_map(flags: &[&str]) -> docopt::ArgvMap {
+        let mut argv = vec!["cargo-script".to_owned(), "script".into()];
+        argv.extend(flags.iter().map(|&f| f.to_owned()));
+        argv.push("x.rs".into());
+        docopt::Docopt::new(USAGE)
+            .map(|d| d.argv(argv.into_iter()))
+            .and_then(|d| d.parse())
+            .unwrap()
+    }
 
-    The tuple members are: the name, absolute path, script contents, last modified time.
-    */
-    File(&'a str, &'a Path, &'a str, u64),
+    #[test]
+    fn test_expand_dep_source() {
+        assert_eq!(expand_dep_source("foo", "0.1.2").unwrap(), "0.1.2");
+        assert_eq!(expand_dep_source("foo", "{ version = \"1\" }").unwrap(), "{ version = \"1\" }");
+        assert_eq!(expand_dep_source("foo", "git:https://example.com/foo.git").unwrap(),
+            "{ git = \"https://example.com/foo.git\" }");
+        assert_eq!(expand_dep_source("foo", "path:/abs/foo").unwrap(), "{ path = \"/abs/foo\" }");
+
+        let rel_path = ::std::env::current_dir().unwrap().join("rel/foo");
+        assert_eq!(expand_dep_source("foo", "path:rel/foo").unwrap(),
+            format!("{{ path = {} }}", ::toml::Value::String(rel_path.to_string_lossy().into_owned())));
+    }
 
-    /**
-    The input is an expression.
+    #[test]
+    fn test_expand_dep_source_empty() {
+        assert!(expand_dep_source("foo", "git:").unwrap_err().is_human());
+        assert!(expand_dep_source("foo", "path:").unwrap_err().is_human());
+    }
 
-    The tuple member is: the script contents.
-    */
-    Expr(&'a str),
+    #[test]
+    fn test_parse_loop_separator() {
+        assert_eq!(parse_loop_separator(",").unwrap(), b',');
+        assert_eq!(parse_loop_separator("\\0").unwrap(), 0);
+        assert_eq!(parse_loop_separator("\\t").unwrap(), b'\t');
+        assert_eq!(parse_loop_separator("\\n").unwrap(), b'\n');
+        assert_eq!(parse_loop_separator("\\\\").unwrap(), b'\\');
+
+        assert!(parse_loop_separator("").unwrap_err().is_human());
+        assert!(parse_loop_separator("ab").unwrap_err().is_human());
+        assert!(parse_loop_separator("\\x").unwrap_err().is_human());
+        assert!(parse_loop_separator("\u{e9}").unwrap_err().is_human());
+    }
 
-    /**
-    The input is a loop expression.
+    #[test]
+    fn test_parse_duration() {
+        assert_eq!(parse_duration("0").unwrap(), 0);
+        assert_eq!(parse_duration("30s").unwrap(), 30 * 1000);
+        assert_eq!(parse_duration("15m").unwrap(), 15 * 60 * 1000);
+        assert_eq!(parse_duration("12h").unwrap(), 12 * 60 * 60 * 1000);
+        assert_eq!(parse_duration("7d").unwrap(), 7 * 24 * 60 * 60 * 1000);
+        assert_eq!(parse_duration("2w").unwrap(), 2 * 7 * 24 * 60 * 60 * 1000);
+
+        assert!(parse_duration("").unwrap_err().is_human());
+        assert!(parse_duration("7").unwrap_err().is_human());
+        assert!(parse_duration("d").unwrap_err().is_human());
+        assert!(parse_duration("7y").unwrap_err().is_human());
+        assert!(parse_duration("-1d").unwrap_err().is_human());
+        assert!(parse_duration("18446744073709551615w").unwrap_err().is_human());
+    }
 
-    The tuple member is: the script contents, whether the `--count` flag was given.
-    */
-    Loop(&'a str, bool),
-}
+    #[test]
+    fn test_trim_expr() {
+        assert_eq!(trim_expr("1 + 1".into()), "1 + 1");
+        assert_eq!(trim_expr("1 + 1\n".into()), "1 + 1");
+        assert_eq!(trim_expr("1 + 1\r\n\n".into()), "1 + 1");
+        assert_eq!(trim_expr("\"a\n\"\n".into()), "\"a\n\"");
+        assert_eq!(trim_expr("-\n".into()), "-");
+    }
 
-impl<'a> Input<'a> {
-    /**
-    Return the "safe name" for the input.  This should be filename-safe.
+    #[test]
+    fn test_expr_file_shares_id() {
+        let meta = PackageMetadata::default();
+        let id = |expr: &str| Input::Expr(expr, ExprStyle::Debug).compute_id(&meta).unwrap();
+        let from_file = trim_expr("1 + 1\n".into());
+        assert_eq!(id(&from_file), id("1 + 1"));
+    }
 
-    Currently, nothing is done to ensure this, other than hoping *really hard* that we don't get fed some excessively bizzare input filename.
-    */
-    pub fn safe_name(&self) -> &str {
-        use Input::*;
+    #[test]
+    fn test_merge_flags_directive_only() {
+        let mut map = argv_map(&[]);
+        merge_flags(&mut map,
+            &argv_map(&["--release", "--dep", "a=1", "--max-jobs", "2"]),
+            &argv_map(&[]));
+        assert!(map.get_bool("--release"));
+        assert_eq!(map.get_vec("--dep"), vec!["a=1"]);
+        assert_eq!(map.get_str("--max-jobs"), "2");
+    }
 
-        match *self {
-            File(name, _, _, _) => name,
-            Expr(..) => "expr",
-            Loop(..) => "loop",
-        }
+    #[test]
+    fn test_merge_flags_command_line_only() {
+        let mut map = argv_map(&["--debug", "--dep", "b=2", "--max-jobs", "4"]);
+        merge_flags(&mut map, &argv_map(&[]), &argv_map(&[]));
+        assert!(map.get_bool("--debug"));
+        assert!(!map.get_bool("--release"));
+        assert_eq!(map.get_vec("--dep"), vec!["b=2"]);
+        assert_eq!(map.get_str("--max-jobs"), "4");
+    }
+
+    #[test]
+    fn test_merge_flags_both() {
+        let mut map = argv_map(&["--debug", "--dep", "b=2", "--max-jobs", "4"]);
+        merge_flags(&mut map,
+            &argv_map(&["--release", "--dep", "a=1", "--max-jobs", "2", "--offline"]),
+            &argv_map(&[]));
+        assert!(map.get_bool("--debug"));
+        assert!(!map.get_bool("--release"));
+        assert_eq!(map.get_vec("--dep"), vec!["a=1", "b=2"]);
+        assert_eq!(map.get_str("--max-jobs"), "4");
+        assert!(map.get_bool("--offline"));
+        assert_eq!(map.get_str("<script>"), "x.rs");
+    }
+
+    #[test]
+    fn test_with_dep_features() {
+        let features = vec!["b".to_owned(), "a".into()];
+        assert_eq!(with_dep_features("foo", "0.1", &features).unwrap(),
+            "{ features = [\"a\", \"b\"], version = \"0.1\" }");
+        assert_eq!(with_dep_features("foo", "{ git = \"https://example.com/foo.git\" }", &features).unwrap(),
+            "{ features = [\"a\", \"b\"], git = \"https://example.com/foo.git\" }");
+        assert_eq!(with_dep_features("foo", "{ version = \"1\", features = [\"c\", \"a\"] }", &features).unwrap(),
+            "{ features = [\"a\", \"b\", \"c\"], version = \"1\" }");
+
+        assert!(with_dep_features("foo", "{ version = ", &features).unwrap_err().is_human());
+        assert!(with_dep_features("foo", "{ features = \"a\" }", &features).unwrap_err().is_human());
+    }
+
+    #[test]
+    fn test_apply_dep_features() {
+        let mut deps = HashMap::new();
+        deps.insert("foo".to_owned(), "0.1".to_owned());
+        deps.insert("bar".to_owned(), "1".to_owned());
+        apply_dep_features(&mut deps, &["foo/x".to_owned(), "foo/y".into(), "foo/x".into()]).unwrap();
+        assert_eq!(deps["foo"], "{ features = [\"x\", \"y\"], version = \"0.1\" }");
+        assert_eq!(deps["bar"], "1");
+
+        assert!(apply_dep_features(&mut deps, &["baz/x".to_owned()]).unwrap_err().is_human());
+        assert!(apply_dep_features(&mut deps, &["foo".to_owned()]).unwrap_err().is_human());
+        assert!(apply_dep_features(&mut deps, &["foo/".to_owned()]).unwrap_err().is_human());
+    }
+
+    #[test]
+    fn test_resolve_script_spellings() {
+        let dir = ::std::env::temp_dir().join("cargo-script-test-resolve");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("sub")).unwrap();
+        fs::File::create(dir.join("x.rs")).unwrap();
+
+        let meta = PackageMetadata::default();
+        let id = |path: &Path| {
+            let (name, path) = resolve_script(path).unwrap();
+            assert_eq!(name, "x");
+            Input::File(&name, &path, "fn main() {}", 0).compute_id(&meta).unwrap()
+        };
+
+        let plain = id(&dir.join("x.rs"));
+        assert_eq!(id(&dir.join("./x.rs")), plain);
+        assert_eq!(id(&dir.join("sub/../x.rs")), plain);
+        assert_eq!(id(Path::new(&format!("{}//x.rs", dir.display()))), plain);
+        assert_eq!(id(Path::new(&format!("{}/./sub/../x.rs", dir.display()))), plain);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_resolve_script_symlink() {
+        let dir = ::std::env::temp_dir().join("cargo-script-test-resolve-symlink");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::File::create(dir.join("x.rs")).unwrap();
+        ::std::os::unix::fs::symlink(dir.join("x.rs"), dir.join("link.rs")).unwrap();
+
+        assert_eq!(resolve_script(&dir.join("link.rs")).unwrap(), resolve_script(&dir.join("x.rs")).unwrap());
+    }
+
+    #[test]
+    fn test_format_timings() {
+        let (compile_time, run_time) = (Duration::from_millis(2345), Duration::from_millis(60));
+        assert_eq!(format_timings(Some(compile_time), Some(run_time)), "compiled in 2.3s, ran in 0.0s");
+        assert_eq!(format_timings(None, Some(run_time)), "cached, ran in 0.0s");
+        assert_eq!(format_timings(Some(compile_time), None), "compiled in 2.3s");
+        assert_eq!(format_timings(None, None), "cached");
     }
 
     /**
-    Compute the package ID for the input.  This is used as the name of the cache folder into which the Cargo package will be generated.
+    Makes a fresh directory tree for testing project configs, with a config in `dir` for each of `configs`.  Returns the root of the tree.
     */
-    pub fn compute_id<'dep, DepIt>(&self, deps: DepIt) -> Result<OsString>
-    where DepIt: IntoIterator<Item=(&'dep str, &'dep str)> {
-        use flate2::FlateWriteExt;
-        use shaman::digest::Digest;
-        use shaman::sha1::Sha1;
-        use Input::*;
-
-        // Hash all the deps now.
-        let mut hasher = Sha1::new();
-        for dep in deps {
-            hasher.input_str("dep=");
-            hasher.input_str(dep.0);
-            hasher.input_str("=");
-            hasher.input_str(dep.1);
-            hasher.input_str(";");
-        }
-
-        match *self {
-            File(name, path, content, _) => {
-                // Deflate-compress the path to the script.
-                let z_path = {
-                    let buf: Vec<u8> = vec![];
-                    let hex = util::Hexify(buf);
-                    let mut z = hex.deflate_encode(flate2::Compression::Best);
-                    try!(write!(z, "{}", path.display()));
-                    let mut buf = try!(z.finish()).0;
-
-                    buf.truncate(consts::DEFLATE_PATH_LEN_MAX);
-                    try!(String::from_utf8(buf)
-                        .map_err(|_| "could not UTF-8 encode deflated path"))
-                };
-
-                // Update the hash with the content.
-                hasher.input_str(&content);
-                let mut digest = hasher.result_str();
-                digest.truncate(consts::CONTENT_DIGEST_LEN_MAX);
-
-                let mut id = OsString::new();
-                id.push("file-");
-                id.push(name);
-                id.push("-");
-                id.push(if STUB_HASHES { "stub" } else { &*z_path });
-                id.push("-");
-                id.push(if STUB_HASHES { "stub" } else { &*digest });
-                Ok(id)
-            },
-            Expr(content) => {
-                hasher.input_str(&content);
-                let mut digest = hasher.result_str();
-                digest.truncate(consts::CONTENT_DIGEST_LEN_MAX);
-
-                let mut id = OsString::new();
-                id.push("expr-");
-                id.push(if STUB_HASHES { "stub" } else { &*digest });
-                Ok(id)
-            },
-            Loop(content, count) => {
-                // Make sure to include the [non-]presence of the `--count` flag in the flag, since it changes the actual generated script output.
-                hasher.input_str("count:");
-                hasher.input_str(if count { "true;" } else { "false;" });
-
-                hasher.input_str(&content);
-                let mut digest = hasher.result_str();
-                digest.truncate(consts::CONTENT_DIGEST_LEN_MAX);
-
-                let mut id = OsString::new();
-                id.push("loop-");
-                id.push(if STUB_HASHES { "stub" } else { &*digest });
-                Ok(id)
-            },
+    fn config_tree(name: &str, configs: &[(&str, &str)]) -> PathBuf {
+        let root = ::std::env::temp_dir().join(format!("cargo-script-test-{}", name));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("a/b")).unwrap();
+        for &(dir, config) in configs {
+            fs::File::create(root.join(dir).join(".cargo-script.toml")).unwrap()
+                .write_all(config.as_bytes()).unwrap();
+        }
+        root
+    }
+
+    #[test]
+    fn test_find_project_config() {
+        let root = config_tree("config", &[(".", "profile = \"release\"\ndeps = [\"time=0.1\"]\n")]);
+        let (config_path, flags) = find_project_config(&root.join("a/b")).unwrap().unwrap();
+        assert_eq!(config_path, root.join(".cargo-script.toml"));
+
+        let mut flags = flags;
+        flags.sort();
+        assert_eq!(flags, vec!["--dep", "--release", "time=0.1"]);
+    }
+
+    #[test]
+    fn test_find_project_config_nearest() {
+        let root = config_tree("config-nearest",
+            &[(".", "profile = \"release\"\n"), ("a", "rustc-flags = [\"-g\"]\n")]);
+        let (config_path, flags) = find_project_config(&root.join("a/b")).unwrap().unwrap();
+        assert_eq!(config_path, root.join("a").join(".cargo-script.toml"));
+        assert_eq!(flags, vec!["--rustc-flag", "-g"]);
+    }
+
+    #[test]
+    fn test_project_config_precedence() {
+        let root = config_tree("config-precedence", &[(".", "profile = \"release\"\ndeps = [\"a=1\"]\n")]);
+        let (_, flags) = find_project_config(&root).unwrap().unwrap();
+        let flags: Vec<&str> = flags.iter().map(|f| &**f).collect();
+
+        let mut map = argv_map(&["--debug", "--dep", "b=2"]);
+        merge_flags(&mut map, &argv_map(&flags), &argv_map(&[]));
+        assert!(map.get_bool("--debug"));
+        assert!(!map.get_bool("--release"));
+        assert_eq!(map.get_vec("--dep"), vec!["a=1", "b=2"]);
+    }
+
+    #[test]
+    fn test_find_project_config_malformed() {
+        let configs = ["deps = [", "deps = \"time\"", "deps = [1]", "profile = \"fast\"", "jobs = 2"];
+        for (i, &config) in configs.iter().enumerate() {
+            let root = config_tree(&format!("config-malformed-{}", i), &[(".", config)]);
+            assert!(find_project_config(&root).unwrap_err().is_human(), "{:?}", config);
+        }
+    }
+
+    #[test]
+    fn test_watch_should_rerun() {
+        assert!(!watch_should_rerun(Some(1000), Some(1000)));
+        assert!(watch_should_rerun(Some(1000), Some(2000)));
+
+        // Missing, whether for a moment or from the start, means wait until there's something there.
+        assert!(!watch_should_rerun(Some(1000), None));
+        assert!(!watch_should_rerun(None, None));
+        assert!(watch_should_rerun(None, Some(1000)));
+    }
+
+    #[test]
+    fn test_run_script_expr_out() {
+        let dir = ::std::env::temp_dir().join("cargo-script-test-expr-out");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let out_path = dir.join("out.txt");
+
+        let mut cmd = ::std::process::Command::new("rustc");
+        cmd.arg("--version");
+        assert_eq!(run_script(cmd, None, Some(&out_path.to_string_lossy())).unwrap(), 0);
+
+        let mut out = String::new();
+        fs::File::open(&out_path).unwrap().read_to_string(&mut out).unwrap();
+        assert!(out.starts_with("rustc "), "{:?}", out);
+    }
+
+    #[test]
+    fn test_run_artifact_args() {
+        let parse = |argv: &[&str]| docopt::Docopt::new(USAGE)
+            .map(|d| d.argv(argv.iter().map(|&a| a.to_owned())))
+            .and_then(|d| d.parse());
+
+        let map = parse(&["cargo-script", "script", "--run-artifact", "x.json", "--", "a", "-b"]).unwrap();
+        assert_eq!(map.get_str("--run-artifact"), "x.json");
+        assert_eq!(map.get_vec("<args>"), vec!["a", "-b"]);
+
+        let map = parse(&["cargo-script", "script", "--run-artifact", "x.json", "a", "b"]).unwrap();
+        assert_eq!(map.get_vec("<args>"), vec!["a", "b"]);
+    }
+
+    #[test]
+    fn test_pinned_dep_version() {
+        let var = format!("{}pin_test_crate", consts::DEP_PIN_ENV_PREFIX);
+        ::std::env::remove_var(&var);
+        assert_eq!(pinned_dep_version("pin-test-crate"), None);
+        assert_eq!(with_dep_version("pin-test-crate".into()), "pin-test-crate=*");
+
+        ::std::env::set_var(&var, " 0.1.2 ");
+        assert_eq!(pinned_dep_version("pin-test-crate"), Some("0.1.2".into()));
+        assert_eq!(pinned_dep_version("pin_test_crate"), Some("0.1.2".into()));
+        assert_eq!(with_dep_version("pin-test-crate".into()), "pin-test-crate=0.1.2");
+        assert_eq!(with_dep_version("pin-test-crate=1".into()), "pin-test-crate=1");
+
+        ::std::env::set_var(&var, "  ");
+        assert_eq!(pinned_dep_version("pin-test-crate"), None);
+        ::std::env::remove_var(&var);
+    }
+
+    #[test]
+    fn test_recursion_depth() {
+        assert_eq!(parse_recursion_depth(None), 0);
+        assert_eq!(parse_recursion_depth(Some("3")), 3);
+        assert_eq!(parse_recursion_depth(Some("three")), 0);
+
+        assert!(check_recursion_depth(0, 10).is_ok());
+        assert!(check_recursion_depth(9, 10).is_ok());
+        let err = check_recursion_depth(10, 10).unwrap_err();
+        assert!(err.is_human());
+        assert!(err.to_string().contains("10 levels deep"), "{}", err);
+        assert!(check_recursion_depth(0, 0).is_err());
+    }
+
+    #[test]
+    fn test_dep_tree_command() {
+        let pkg_path = Path::new("pkg");
+        let mani_path = pkg_path.join("Cargo.toml");
+        let cmd = format!("{:?}", dep_tree_command(pkg_path, &PackageMetadata::default(), &BuildSettings::default()));
+        assert!(cmd.contains(&format!("\"tree\" \"--manifest-path\" {:?}", mani_path.to_string_lossy())), "{}", cmd);
+        assert!(!cmd.contains("--target") && !cmd.contains("--offline"), "{}", cmd);
+
+        let meta = PackageMetadata { target: Some("x86_64-unknown-linux-gnu".into()), ..PackageMetadata::default() };
+        let settings = BuildSettings { color: Color::Never, offline: true, ..BuildSettings::default() };
+        let cmd = format!("{:?}", dep_tree_command(pkg_path, &meta, &settings));
+        assert!(cmd.contains("\"--target\" \"x86_64-unknown-linux-gnu\""), "{}", cmd);
+        assert!(cmd.contains("\"--color\" \"never\""), "{}", cmd);
+        assert!(cmd.contains("\"--offline\""), "{}", cmd);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_run_all_bins_keep_going() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let pkg_path = ::std::env::temp_dir().join("cargo-script-test-run-all");
+        let _ = fs::remove_dir_all(&pkg_path);
+        fs::create_dir_all(&pkg_path).unwrap();
+        fs::File::create(pkg_path.join("Cargo.toml")).unwrap()
+            .write_all(b"[package]\nname = \"n\"\n[[bin]]\nname = \"a\"\n[[bin]]\nname = \"b\"\n[[bin]]\nname = \"c\"\n")
+            .unwrap();
+
+        // Each binary leaves a mark when it runs; `b` and `c` fail.
+        let meta = PackageMetadata::default();
+        for &(bin, code) in &[("a", 0), ("b", 2), ("c", 3)] {
+            let exe_path = get_bin_path(&pkg_path, &meta, bin);
+            fs::create_dir_all(exe_path.parent().unwrap()).unwrap();
+            let mark = pkg_path.join(format!("{}.ran", bin));
+            fs::File::create(&exe_path).unwrap()
+                .write_all(format!("#!/bin/sh\ntouch '{}'\nexit {}\n", mark.display(), code).as_bytes()).unwrap();
+            fs::set_permissions(&exe_path, fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let ran = |bin: &str| fs::metadata(pkg_path.join(format!("{}.ran", bin))).is_ok();
+
+        assert_eq!(run_all_bins(&pkg_path, &meta, &[], 0, false).unwrap(), 2);
+        assert!(ran("a") && ran("b") && !ran("c"));
+
+        for bin in &["a", "b", "c"] {
+            let _ = fs::remove_file(pkg_path.join(format!("{}.ran", bin)));
+        }
+        assert_eq!(run_all_bins(&pkg_path, &meta, &[], 0, true).unwrap(), 2);
+        assert!(ran("a") && ran("b") && ran("c"));
+    }
+
+    #[test]
+    fn test_write_cache_stats() {
+        let cache_path = ::std::env::temp_dir().join("cargo-script-test-stats");
+        let _ = fs::remove_dir_all(&cache_path);
+        let meta_str = ::rustc_serialize::json::encode(&PackageMetadata::default()).unwrap();
+        for &(id, meta) in &[("file-a", Some(&*meta_str)), ("expr-b", Some(&*meta_str)),
+                ("stdin-c", Some("{ nope")), ("loop-d", None)] {
+            let pkg_path = cache_path.join(id);
+            fs::create_dir_all(&pkg_path).unwrap();
+            if let Some(meta) = meta {
+                fs::File::create(pkg_path.join(consts::METADATA_FILE)).unwrap()
+                    .write_all(meta.as_bytes()).unwrap();
+            }
+        }
+
+        let stats = |cutoff| {
+            let mut out = vec![];
+            write_cache_stats(&mut out, &cache_path, cutoff).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+
+        let out = stats(0);
+        assert!(out.contains("packages:   4 (1 file, 1 stdin, 1 expr, 1 loop)\n"), "{}", out);
+        assert!(out.contains("unreadable: 2\n"), "{}", out);
+        assert!(out.contains("stale:      0\n"), "{}", out);
+
+        // Everything with metadata, readable or not, is older than the end of time.
+        let out = stats(!0);
+        assert!(out.contains("stale:      3\n"), "{}", out);
+    }
+
+    #[test]
+    fn test_check_rustc_flags() {
+        assert!(check_rustc_flags(&[]).is_ok());
+        assert!(check_rustc_flags(&["-g".to_owned(), "-C opt-level=1".into()]).is_ok());
+        assert!(check_rustc_flags(&["-g".to_owned(), "".into()]).unwrap_err().is_human());
+        assert!(check_rustc_flags(&[" ".to_owned()]).unwrap_err().is_human());
+    }
+
+    #[test]
+    fn test_get_argv_hashbang() {
+        let dir = ::std::env::temp_dir().join("cargo-script-test-hashbang");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("hello.rs");
+        fs::File::create(&script).unwrap().write_all(b"#!/usr/bin/env cargo-script\nfn main() {}\n").unwrap();
+        let script = script.to_string_lossy().into_owned();
+        let argv = |args: &[&str]| args.iter().map(|&a| a.to_owned()).collect::<Vec<_>>();
+
+        // Run by the hashbang line: the script's own arguments mustn't be taken for ours.
+        assert_eq!(get_argv(argv(&["/usr/bin/cargo-script", &script, "--release", "x"])),
+            argv(&["/usr/bin/cargo-script", "script", &script, "--", "--release", "x"]));
+
+        // Run through Cargo.
+        assert_eq!(get_argv(argv(&["/usr/bin/cargo-script", "script", &script, "x"])),
+            argv(&["/usr/bin/cargo-script", "script", &script, "x"]));
+
+        // Not a script at all.
+        assert_eq!(get_argv(argv(&["/usr/bin/cargo-script", "--help"])),
+            argv(&["/usr/bin/cargo-script", "--help"]));
+    }
+
+    #[test]
+    fn test_resolve_profile() {
+        assert_eq!(resolve_profile(false, false, None).unwrap(), false);
+        assert_eq!(resolve_profile(false, false, Some("debug")).unwrap(), true);
+        assert_eq!(resolve_profile(false, false, Some("release")).unwrap(), false);
+        assert!(resolve_profile(false, false, Some("fast")).unwrap_err().is_human());
+
+        // Flags win over the environment.
+        assert_eq!(resolve_profile(false, true, Some("debug")).unwrap(), false);
+        assert_eq!(resolve_profile(true, false, Some("release")).unwrap(), true);
+        assert_eq!(resolve_profile(true, false, Some("fast")).unwrap(), true);
+
+        assert!(resolve_profile(true, true, None).unwrap_err().is_human());
+    }
+
+    #[test]
+    fn test_debug_and_release_conflict() {
+        let map = argv_map(&["--debug", "--release"]);
+        assert!(map.get_bool("--debug") && map.get_bool("--release"));
+        assert!(resolve_profile(map.get_bool("--debug"), map.get_bool("--release"), None).unwrap_err().is_human());
+
+        // One from a directive and the other from the command line is fine: the command line wins.
+        let mut map = argv_map(&["--debug"]);
+        merge_flags(&mut map, &argv_map(&["--release"]), &argv_map(&[]));
+        assert_eq!(resolve_profile(map.get_bool("--debug"), map.get_bool("--release"), Some("release")).unwrap(), true);
+    }
+
+    #[test]
+    fn test_write_cache_list() {
+        let cache_path = ::std::env::temp_dir().join("cargo-script-test-list-cache");
+        let _ = fs::remove_dir_all(&cache_path);
+        let meta = PackageMetadata {
+            path: Some("/scripts/a.rs".into()),
+            debug: true,
+            deps: vec![("time".into(), "0.1".into()), ("libc".into(), "*".into())],
+            ..PackageMetadata::default()
+        };
+        let meta_str = ::rustc_serialize::json::encode(&meta).unwrap();
+        for &(id, meta) in &[("file-a", &*meta_str), ("stdin-b", "{ nope")] {
+            let pkg_path = cache_path.join(id);
+            fs::create_dir_all(&pkg_path).unwrap();
+            fs::File::create(pkg_path.join(consts::METADATA_FILE)).unwrap().write_all(meta.as_bytes()).unwrap();
+        }
+
+        let mut out = vec![];
+        write_cache_list(&mut out, &cache_path).unwrap();
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines, vec![
+            "ID\tPROFILE\tDEPS\tSIZE\tSCRIPT".to_owned(),
+            format!("file-a\tdebug\t2\t{}\t/scripts/a.rs", meta_str.len()),
+            "stdin-b\tcorrupt\t-\t6\t-".into(),
+        ]);
+    }
+
+    #[test]
+    fn test_quiet_implies_quiet_cargo() {
+        let settings = |flags: &[&str]| build_settings(&argv_map(flags).decode::<Args>().unwrap());
+
+        let plain = settings(&[]);
+        assert!(!plain.quiet && !plain.quiet_cargo);
+
+        let quiet_cargo = settings(&["--quiet-cargo"]);
+        assert!(!quiet_cargo.quiet && quiet_cargo.quiet_cargo);
+
+        let quiet = settings(&["--quiet"]);
+        assert!(quiet.quiet && quiet.quiet_cargo);
+    }
+
+    #[test]
+    fn test_get_built_exe_path_missing() {
+        let dir = ::std::env::temp_dir().join("cargo-script-test-missing-exe");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let exe_path = dir.join("gone");
+        let meta = PackageMetadata {
+            debug: true,
+            exe_path: Some(exe_path.to_string_lossy().into_owned()),
+            ..PackageMetadata::default()
+        };
+        let input = Input::Expr("1", ExprStyle::Debug);
+
+        // That Cargo put it somewhere else is our problem, not the user's.
+        let err = get_built_exe_path(&input, &dir, &meta).unwrap_err();
+        assert!(!err.is_human());
+        let msg = err.to_string();
+        assert!(msg.contains(&format!("{:?}", exe_path)), "{}", msg);
+        assert!(msg.contains("debug"), "{}", msg);
+
+        fs::File::create(&exe_path).unwrap();
+        assert_eq!(get_built_exe_path(&input, &dir, &meta).unwrap(), exe_path);
+    }
+
+    #[test]
+    fn test_verbosity_count() {
+        let verbosity = |flags: &[&str]| argv_map(flags).decode::<Args>().unwrap().flag_verbose;
+
+        assert_eq!(verbosity(&[]), 0);
+        assert_eq!(verbosity(&["-v"]), 1);
+        assert_eq!(verbosity(&["-vv"]), 2);
+        assert_eq!(verbosity(&["-v", "--verbose"]), 2);
+    }
+
+    #[test]
+    fn test_find_script_search_path() {
+        let dir = ::std::env::temp_dir().join("cargo-script-test-search-path");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("scripts")).unwrap();
+        fs::File::create(dir.join("scripts").join("on-path.rs")).unwrap();
+
+        let search_path = ::std::env::join_paths(vec![dir.join("nowhere"), dir.join("scripts")]).unwrap();
+        ::std::env::set_var(consts::SCRIPT_PATH_ENV, &search_path);
+
+        let found = find_script("on-path").map(|(path, _)| path);
+        assert_eq!(found, Some(dir.join("scripts").join("on-path.rs")));
+        assert!(find_script("not-on-path").is_none());
+
+        // An absolute path means exactly that file.
+        assert!(find_script(dir.join("on-path")).is_none());
+
+        ::std::env::remove_var(consts::SCRIPT_PATH_ENV);
+        assert!(find_script("on-path").is_none());
+    }
+
+    #[test]
+    fn test_color_flag() {
+        assert!(argv_map(&["--color", "purple"]).decode::<Args>().is_err());
+
+        let args = argv_map(&["--color", "never"]).decode::<Args>().unwrap();
+        assert_eq!(args.flag_color, Color::Never);
+        let cmd = cargo_command("build", Path::new("pkg"), &PackageMetadata::default(), &build_settings(&args));
+        assert!(format!("{:?}", cmd).contains("\"--color\" \"never\""), "{:?}", cmd);
+
+        let args = argv_map(&[]).decode::<Args>().unwrap();
+        assert_eq!(args.flag_color, Color::Auto);
+    }
+
+    #[test]
+    fn test_check_package() {
+        let check = |name: &str, script: &str| {
+            let pkg_path = ::std::env::temp_dir().join(format!("cargo-script-test-check-{}", name));
+            let _ = fs::remove_dir_all(&pkg_path);
+            let input = Input::Stdin(script);
+            let (meta, settings) = (PackageMetadata::default(), BuildSettings::default());
+            generate_package(&input, &meta, &pkg_path, &settings).unwrap();
+            check_package(&input, &pkg_path, &meta, &settings)
+        };
+
+        assert_eq!(check("good", "fn main() {}").unwrap(), 0);
+        match check("bad", "fn main() { let x: u32 = \"nope\"; }") {
+            Err(MainError::Compile(ref name, code)) => assert!(name == "stdin" && code != 0),
+            other => panic!("expected a compile error, got {:?}", other)
         }
     }
+
+    #[test]
+    fn test_find_project_config_path_dep() {
+        let root = config_tree("config-path-dep",
+            &[(".", "deps = [\"lib=path:lib\", \"abs=path:/abs\", \"time=0.1\"]\n")]);
+        let (_, flags) = find_project_config(&root.join("a/b")).unwrap().unwrap();
+        let lib_dep = format!("lib=path:{}", root.join("lib").display());
+        assert_eq!(flags, vec!["--dep", &*lib_dep, "--dep", "abs=path:/abs", "--dep", "time=0.1"]);
+
+        // Wherever it's run from, it's the same folder next to the config.
+        let lib_path = ::toml::Value::String(root.join("lib").to_string_lossy().into_owned());
+        assert_eq!(expand_dep_source("lib", &lib_dep["lib=".len()..]).unwrap(),
+            format!("{{ path = {} }}", lib_path));
+
+        assert_eq!(resolve_config_dep("empty=path:", &root), "empty=path:");
+    }
+
+    #[test]
+    fn test_script_args_after_double_dash() {
+        let argv = vec!["cargo-script", "script", "foo.rs", "--", "--debug"];
+        let args: Args = docopt::Docopt::new(USAGE)
+            .map(|d| d.argv(argv.into_iter().map(String::from)))
+            .and_then(|d| d.decode())
+            .unwrap();
+        assert_eq!(args.arg_script, Some("foo.rs".into()));
+        assert_eq!(args.arg_args, vec!["--debug"]);
+        assert!(!args.flag_debug);
+    }
 }
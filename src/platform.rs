@@ -2,7 +2,7 @@
 This module is for platform-specific stuff.
 */
 
-pub use self::inner::get_cache_dir_for;
+pub use self::inner::{get_cache_dir_for, get_config_dir_for, stdout_supports_color};
 
 #[cfg(windows)]
 pub mod inner {
@@ -18,7 +18,7 @@ pub mod inner {
     use std::path::{Path, PathBuf};
     use std::mem;
     use std::os::windows::ffi::OsStringExt;
-    use self::uuid::FOLDERID_LocalAppData;
+    use self::uuid::{FOLDERID_LocalAppData, FOLDERID_RoamingAppData};
     use error::MainError;
 
     /**
@@ -33,6 +33,27 @@ pub mod inner {
         Ok(Path::new(&dir).to_path_buf().join(product))
     }
 
+    /**
+    Get a directory suitable for storing user-specific configuration.
+
+    On Windows, this is RoamingAppData, so that it follows the user around.
+    */
+    pub fn get_config_dir_for<P>(product: P) -> Result<PathBuf, MainError>
+    where P: AsRef<Path> {
+        let dir = try!(SHGetKnownFolderPath(&FOLDERID_RoamingAppData, 0, ::std::ptr::null_mut())
+            .map_err(|e| e.to_string()));
+        Ok(Path::new(&dir).to_path_buf().join(product))
+    }
+
+    /**
+    Can we colour what we write to stdout?
+
+    The Windows console doesn't understand ANSI escape codes, so no.
+    */
+    pub fn stdout_supports_color() -> bool {
+        false
+    }
+
     type WinResult<T> = Result<T, WinError>;
 
     struct WinError(winapi::HRESULT);
@@ -83,3 +104,18 @@ pub mod inner {
         len
     }
 }
+
+#[cfg(all(test, windows))]
+mod tests {
+    use super::{get_cache_dir_for, get_config_dir_for};
+    use std::path::Path;
+
+    #[test]
+    fn test_get_config_dir_for() {
+        let config_dir = get_config_dir_for("cargo-script").unwrap();
+        assert_eq!(config_dir, Path::new(&::std::env::var_os("APPDATA").unwrap()).join("cargo-script"));
+
+        // Config roams with the user; the cache stays on the machine.
+        assert!(config_dir != get_cache_dir_for("cargo-script").unwrap());
+    }
+}
@@ -1,8 +1,13 @@
 /*!
 This module just contains other random implementation stuff.
 */
+use std::fmt::Display;
+use std::fs;
 use std::io;
 use std::io::prelude::*;
+use std::marker::PhantomData;
+use std::path::Path;
+use std::time::Duration;
 
 /**
 A `Write` filter that turns everything into lowercase hex text.
@@ -25,3 +30,61 @@ where W: Write {
         self.0.flush()
     }
 }
+
+/**
+Used to defer a closure until the value is dropped.
+
+The closure *must* return a `Result<(), _>`, as a reminder to *not* panic; doing so will abort your whole program if it happens during another panic.  If the closure returns an `Err`, then it is logged as an `error`.
+*/
+#[must_use]
+pub struct Defer<F, E>(Option<F>, PhantomData<E>)
+where F: FnOnce() -> Result<(), E>, E: Display;
+
+impl<F, E> Defer<F, E>
+where F: FnOnce() -> Result<(), E>, E: Display {
+    /**
+    Create a new `Defer` with the given closure.
+    */
+    pub fn defer(f: F) -> Defer<F, E> {
+        Defer(Some(f), PhantomData)
+    }
+}
+
+impl<F, E> Drop for Defer<F, E>
+where F: FnOnce() -> Result<(), E>, E: Display {
+    fn drop(&mut self) {
+        if let Some(f) = self.0.take() {
+            if let Err(err) = f() {
+                error!("deferred function failed: {}", err);
+            }
+        }
+    }
+}
+
+/**
+Runs a closure, returning its result along with how long it took.
+*/
+pub fn time<F, T>(f: F) -> (T, Duration)
+where F: FnOnce() -> T {
+    let mut result = None;
+    let elapsed = Duration::span(|| result = Some(f()));
+    (result.expect("timed closure did not produce a result"), elapsed)
+}
+
+/**
+Works out the total size, in bytes, of all the files in a directory and its subdirectories.
+*/
+pub fn dir_size<P>(path: P) -> io::Result<u64>
+where P: AsRef<Path> {
+    let mut size = 0;
+    for entry in try!(fs::read_dir(path)) {
+        let entry = try!(entry);
+        let md = try!(fs::symlink_metadata(entry.path()));
+        if md.is_dir() {
+            size += try!(dir_size(entry.path()));
+        } else {
+            size += md.len();
+        }
+    }
+    Ok(size)
+}
@@ -0,0 +1,31 @@
+/*!
+Drives the library directly, the way a program embedding `cargo-script` would.
+*/
+extern crate cargo_script;
+
+use std::process::Command;
+use cargo_script::{build_input, consts};
+use cargo_script::{BuildSettings, ExprStyle, Input, PackageMetadata};
+
+#[test]
+fn test_build_input() {
+    let cache_path = std::env::temp_dir().join("cargo-script-test-build-input");
+    std::env::set_var(consts::CACHE_DIR_ENV, &cache_path);
+
+    let input = Input::Expr("6 * 7", ExprStyle::Debug);
+    let settings = BuildSettings {
+        quiet_cargo: true,
+        quiet: true,
+        ..BuildSettings::default()
+    };
+
+    let exe_path = build_input(&input, PackageMetadata::default(), &settings).unwrap();
+    assert!(exe_path.starts_with(&cache_path));
+
+    let out = Command::new(&exe_path).output().unwrap();
+    assert!(out.status.success());
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "42");
+
+    // The second time around, it's already cached.
+    assert_eq!(build_input(&input, PackageMetadata::default(), &settings).unwrap(), exe_path);
+}
@@ -0,0 +1,392 @@
+/*!
+Runs the `cargo-script` binary itself, the way a user would.
+*/
+extern crate cargo_script;
+
+use std::fs;
+use std::io::BufReader;
+use std::io::prelude::*;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Output, Stdio};
+use cargo_script::consts;
+
+/**
+Makes a fresh directory for a test, containing each of `files`.
+*/
+fn test_dir(name: &str, files: &[(&str, &str)]) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("cargo-script-test-cli-{}", name));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    for &(file, content) in files {
+        fs::File::create(dir.join(file)).unwrap().write_all(content.as_bytes()).unwrap();
+    }
+    dir
+}
+
+/**
+Runs `cargo script` with the given arguments from within `dir`, using a cache of its own in there.  The script gets an empty stdin.
+*/
+fn cargo_script(dir: &Path, args: &[&str]) -> Output {
+    cargo_script_with_stdin(dir, args, "")
+}
+
+/**
+Like `cargo_script`, but feeds `stdin` to whatever gets run.
+*/
+fn cargo_script_with_stdin(dir: &Path, args: &[&str], stdin: &str) -> Output {
+    let mut child = spawn_cargo_script(dir, args);
+    child.stdin.take().unwrap().write_all(stdin.as_bytes()).unwrap();
+    child.wait_with_output().unwrap()
+}
+
+/**
+Starts `cargo script` as `cargo_script` would, but leaves talking to it up to the caller.
+*/
+fn spawn_cargo_script(dir: &Path, args: &[&str]) -> Child {
+    // Tests live in `deps`, next to the binaries' folder.
+    let mut exe_path = std::env::current_exe().unwrap();
+    exe_path.pop();
+    if exe_path.ends_with("deps") {
+        exe_path.pop();
+    }
+    let exe_path = exe_path.join(format!("cargo-script{}", std::env::consts::EXE_SUFFIX));
+
+    Command::new(exe_path)
+        .arg("script")
+        .args(args)
+        .current_dir(dir)
+        .env(consts::CACHE_DIR_ENV, dir.join("cache"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap()
+}
+
+fn read_file(path: &Path) -> String {
+    let mut content = String::new();
+    fs::File::open(path).unwrap().read_to_string(&mut content).unwrap();
+    content
+}
+
+/**
+A `--post-run` hook which writes the exit code it's given to `hook.txt`.
+*/
+fn exit_code_hook() -> &'static str {
+    if cfg!(windows) {
+        "echo %CARGO_SCRIPT_EXIT_CODE%> hook.txt"
+    } else {
+        "echo $CARGO_SCRIPT_EXIT_CODE > hook.txt"
+    }
+}
+
+#[test]
+fn test_post_run_hook() {
+    let dir = test_dir("post-run", &[
+        ("ok.rs", "fn main() {}"),
+        ("fail.rs", "fn main() { std::process::exit(3); }"),
+    ]);
+
+    let out = cargo_script(&dir, &["--post-run", exit_code_hook(), "ok.rs"]);
+    assert_eq!(out.status.code(), Some(0));
+    assert_eq!(read_file(&dir.join("hook.txt")).trim(), "0");
+
+    let out = cargo_script(&dir, &["--post-run", exit_code_hook(), "fail.rs"]);
+    assert_eq!(out.status.code(), Some(3));
+    assert_eq!(read_file(&dir.join("hook.txt")).trim(), "3");
+}
+
+#[test]
+fn test_manifest_env() {
+    let dir = test_dir("env", &[("env.rs", r#"
+[package.metadata.cargo-script.env]
+A = "manifest"
+B = "manifest"
+---
+fn main() {
+    println!("{} {}", std::env::var("A").unwrap(), std::env::var("B").unwrap());
+}
+"#)]);
+
+    let out = cargo_script(&dir, &["env.rs"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "manifest manifest");
+
+    let out = cargo_script(&dir, &["--env", "B=command-line", "env.rs"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "manifest command-line");
+}
+
+#[test]
+fn test_cargo_subcommand() {
+    let dir = test_dir("cargo-subcommand", &[("sub.rs", "fn main() {}")]);
+
+    let out = cargo_script(&dir, &["--cargo-subcommand", "metadata", "sub.rs",
+        "--", "--no-deps", "--format-version", "1"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("\"name\":\"sub\""), "{}", stdout);
+
+    // It runs on a temporary package, so the cache isn't touched.
+    assert!(fs::metadata(dir.join("cache")).is_err());
+}
+
+#[test]
+fn test_loop_empty_stdin() {
+    let dir = test_dir("loop-empty", &[]);
+
+    let out = cargo_script_with_stdin(&dir, &["--loop", "|l| l.len()"], "");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "");
+
+    let out = cargo_script_with_stdin(&dir, &["--count", "--loop", "|l, n| (n, l.len())"], "");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "");
+
+    // Just to be sure the closures would have said something given the chance.
+    let out = cargo_script_with_stdin(&dir, &["--count", "--loop", "|l, n| (n, l.len())"], "ab\n");
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "(1, 3)\n");
+}
+
+#[test]
+fn test_warm() {
+    let dir = test_dir("warm", &[("warm.rs", "fn main() { println!(\"ran\"); }")]);
+
+    let out = cargo_script(&dir, &["--warm", "warm.rs"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.trim().ends_with("warm.rs: warmed"), "{}", stdout);
+    assert!(!stdout.contains("ran"), "{}", stdout);
+
+    let out = cargo_script(&dir, &["--warm", "warm.rs"]);
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.trim().ends_with("warm.rs: already cached"), "{}", stdout);
+
+    let out = cargo_script(&dir, &["warm.rs"]);
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), "ran");
+}
+
+#[test]
+fn test_run_tests_then_main_failing() {
+    let dir = test_dir("run-tests-failing", &[("tested.rs", r#"
+fn main() { println!("main ran"); }
+
+#[test]
+fn test_fails() { panic!("nope"); }
+"#)]);
+
+    let out = cargo_script(&dir, &["--run-tests-then-main", "--timings", "--post-run", exit_code_hook(), "tested.rs"]);
+    let code = out.status.code().unwrap();
+    assert!(code != 0);
+    assert!(!String::from_utf8_lossy(&out.stdout).contains("main ran"));
+    assert_eq!(read_file(&dir.join("hook.txt")).trim(), code.to_string());
+
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("compiled in "), "{}", stderr);
+}
+
+#[test]
+fn test_quiet_cargo() {
+    let dir = test_dir("quiet-cargo", &[("quiet.rs", "fn main() {}")]);
+
+    let out = cargo_script(&dir, &["--quiet-cargo", "quiet.rs"]);
+    assert!(out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(!stderr.contains("Compiling"), "{}", stderr);
+
+    // Our own messages still get through.
+    let out = cargo_script(&dir, &["--quiet-cargo", "--clean", "quiet.rs"]);
+    assert!(String::from_utf8_lossy(&out.stdout).trim().ends_with("quiet.rs: removed"));
+}
+
+#[test]
+fn test_stdin_script_is_cached_by_content() {
+    let dir = test_dir("stdin-cache", &[]);
+    let run = |source: &str| {
+        let out = cargo_script_with_stdin(&dir, &["--timings", "-"], source);
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        String::from_utf8_lossy(&out.stderr).into_owned()
+    };
+
+    assert!(run("fn main() { println!(\"one\"); }").contains("compiled in "));
+    assert!(run("fn main() { println!(\"one\"); }").contains("cached, ran in "));
+    assert!(run("fn main() { println!(\"two\"); }").contains("compiled in "));
+
+    let pkgs = fs::read_dir(dir.join("cache")).unwrap()
+        .filter(|e| e.as_ref().unwrap().file_name().to_string_lossy().starts_with("stdin-"))
+        .count();
+    assert_eq!(pkgs, 2);
+}
+
+#[test]
+fn test_check() {
+    let dir = test_dir("check", &[
+        ("good.rs", "fn main() { println!(\"ran\"); }"),
+        ("bad.rs", "fn main() { let x: u32 = \"nope\"; }"),
+    ]);
+
+    let out = cargo_script(&dir, &["--check", "good.rs"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "");
+    assert!(fs::metadata(dir.join("cache")).is_err());
+
+    let out = cargo_script(&dir, &["--check", "bad.rs"]);
+    assert!(!out.status.success());
+    assert!(String::from_utf8_lossy(&out.stderr).contains("mismatched types"));
+}
+
+#[test]
+fn test_clean_missing_entry() {
+    let dir = test_dir("clean-missing", &[
+        ("never.rs", "fn main() {}"),
+        ("other.rs", "fn main() {}"),
+    ]);
+    assert!(cargo_script(&dir, &["other.rs"]).status.success());
+
+    let out = cargo_script(&dir, &["--clean", "never.rs"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(String::from_utf8_lossy(&out.stdout).trim().ends_with("never.rs: not cached"));
+
+    // Cleaning it doesn't build it on the sly.
+    let out = cargo_script(&dir, &["--clean", "never.rs"]);
+    assert!(String::from_utf8_lossy(&out.stdout).trim().ends_with("never.rs: not cached"));
+
+    // Nor does it touch anything else.
+    let out = cargo_script(&dir, &["--clean", "other.rs"]);
+    assert!(String::from_utf8_lossy(&out.stdout).trim().ends_with("other.rs: removed"));
+}
+
+#[test]
+fn test_no_cache() {
+    let dir = test_dir("no-cache", &[("fleeting.rs", r#"
+fn main() {
+    println!("{}", std::env::current_exe().unwrap().display());
+    std::process::exit(3);
+}
+"#)]);
+
+    let out = cargo_script(&dir, &["--no-cache", "fleeting.rs"]);
+    assert_eq!(out.status.code(), Some(3), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(fs::metadata(dir.join("cache")).is_err());
+
+    // The temporary package goes, even though the script failed.
+    let exe_path = PathBuf::from(String::from_utf8_lossy(&out.stdout).trim());
+    assert!(exe_path.starts_with(std::env::temp_dir()), "{:?}", exe_path);
+    assert!(fs::metadata(&exe_path).is_err(), "{:?}", exe_path);
+}
+
+#[test]
+fn test_gen_pkg_only_non_empty() {
+    let dir = test_dir("gen-pkg-only", &[("gen.rs", "fn main() {}")]);
+    fs::create_dir(dir.join("pkg")).unwrap();
+    fs::File::create(dir.join("pkg").join("keep.txt")).unwrap();
+
+    let out = cargo_script(&dir, &["--gen-pkg-only", "pkg", "gen.rs"]);
+    assert!(!out.status.success());
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("is not empty; use --force"), "{}", stdout);
+    assert!(fs::metadata(dir.join("pkg").join("Cargo.toml")).is_err());
+
+    let out = cargo_script(&dir, &["--gen-pkg-only", "pkg", "--force", "gen.rs"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert!(fs::metadata(dir.join("pkg").join("Cargo.toml")).is_ok());
+    assert!(fs::metadata(dir.join("pkg").join("keep.txt")).is_ok());
+}
+
+#[test]
+fn test_manifest_only() {
+    let dir = test_dir("manifest-only", &[("mani.rs", r#"
+//! ```cargo
+//! [dependencies]
+//! log = "0.3"
+//! ```
+fn main() {}
+"#)]);
+
+    let out = cargo_script(&dir, &["--manifest-only", "--dep", "time=0.1.25", "mani.rs"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    let stdout = String::from_utf8_lossy(&out.stdout);
+    assert!(stdout.contains("[package]\nauthors = [\"Anonymous\"]\nname = \"mani\"\n"), "{}", stdout);
+    assert!(stdout.contains("[dependencies]\nlog = \"0.3\"\ntime = \"0.1.25\"\n"), "{}", stdout);
+
+    // Nothing gets written or built.
+    assert!(fs::metadata(dir.join("cache")).is_err());
+}
+
+#[test]
+fn test_loop_streams_lines() {
+    let dir = test_dir("loop-stream", &[]);
+
+    let mut child = spawn_cargo_script(&dir, &["--count", "--loop", "|l, n| (n, l.trim_right().to_owned())"]);
+    let mut stdin = child.stdin.take().unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+
+    // Each line's output has to turn up before the next line is even written, let alone stdin closed.
+    let mut line = String::new();
+    for (n, input) in ["one", "two"].iter().enumerate() {
+        writeln!(stdin, "{}", input).unwrap();
+        stdin.flush().unwrap();
+        line.clear();
+        stdout.read_line(&mut line).unwrap();
+        assert_eq!(line, format!("({}, \"{}\")\n", n + 1, input));
+    }
+
+    // The last line doesn't need a newline to count.
+    write!(stdin, "three").unwrap();
+    drop(stdin);
+    line.clear();
+    stdout.read_to_string(&mut line).unwrap();
+    assert_eq!(line, "(3, \"three\")\n");
+    assert!(child.wait().unwrap().success());
+}
+
+#[test]
+fn test_run_args_file() {
+    let dir = test_dir("run-args-file", &[
+        ("args.rs", "fn main() { println!(\"{:?}\", std::env::args().skip(1).collect::<Vec<_>>()); }"),
+        ("args.txt", "two words\n\n   \n--flag\r\nlast"),
+        ("other.txt", "other"),
+    ]);
+
+    let out = cargo_script(&dir, &["--run-args-file", "args.txt", "args.rs", "first"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), r#"["first", "two words", "--flag", "last"]"#);
+
+    // Different arguments are still the same script, as far as the cache is concerned.
+    let out = cargo_script(&dir, &["--timings", "--run-args-file", "other.txt", "args.rs"]);
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), r#"["other"]"#);
+    assert!(String::from_utf8_lossy(&out.stderr).contains("cached, ran in "));
+}
+
+#[test]
+fn test_stdin_file() {
+    let dir = test_dir("stdin-file", &[
+        ("cat.rs", "use std::io::prelude::*;\n\
+            fn main() { let mut s = String::new(); std::io::stdin().read_to_string(&mut s).unwrap(); print!(\"[{}]\", s); }"),
+        ("input.txt", "from\nthe file"),
+    ]);
+
+    // The file takes the place of our own stdin entirely.
+    let out = cargo_script_with_stdin(&dir, &["--stdin-file", "input.txt", "cat.rs"], "from the pipe");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "[from\nthe file]");
+
+    // Including for --loop, whose lines come from stdin.
+    let out = cargo_script_with_stdin(&dir,
+        &["--stdin-file", "input.txt", "--loop", "|l| l.trim_right().len()"], "pipe\n");
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout), "4\n8\n");
+}
+
+#[test]
+fn test_script_args_after_double_dash() {
+    let dir = test_dir("double-dash", &[("foo.rs", r#"
+fn main() {
+    println!("{:?} {}", std::env::args().skip(1).collect::<Vec<_>>(), cfg!(debug_assertions));
+}
+"#)]);
+
+    let out = cargo_script(&dir, &["foo.rs", "--", "--debug"]);
+    assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+    assert_eq!(String::from_utf8_lossy(&out.stdout).trim(), r#"["--debug"] false"#);
+}